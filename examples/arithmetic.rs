@@ -0,0 +1,42 @@
+//! Measures the interpreter loop on arithmetic-heavy code, run with `cargo run --release --example arithmetic`
+
+use std::time::Duration;
+use std::time::Instant;
+
+use lox::chunk::Chunk;
+use lox::compiler;
+use lox::compiler::CompilerOptions;
+use lox::verifier::VerifiedChunk;
+use lox::vm::InterpretError;
+use lox::vm::VM;
+
+const TERMS: usize = 50_000;
+const RUNS: usize = 20;
+
+fn main() {
+	let mut source = String::from("0");
+	for i in 0..TERMS {
+		let op = [ "+", "-", "*", "/" ][i % 4];
+		source.push_str(&format!(" {op} -({} + {})", i % 7 + 1, i % 5 + 1));
+	}
+	let options = CompilerOptions { fold_constants: false, peephole: false };
+	let chunk = compiler::compile_with_options(&source, options).expect("benchmark script compiles");
+	let verified_chunk = VerifiedChunk::new(&chunk, 0).expect("benchmark script verifies");
+
+	report_runs("interpret", &chunk, |vm| vm.interpret(&chunk));
+	report_runs("run_verified", &chunk, |vm| vm.run_verified(verified_chunk));
+}
+
+fn report_runs(name: &str, chunk: &Chunk, mut run: impl FnMut(&mut VM<64>) -> Result<(), InterpretError>) {
+	let mut vm = VM::<64>::new();
+	vm.set_output(Box::new(std::io::sink()));
+	let mut fastest = Duration::MAX;
+	for _ in 0..RUNS {
+		let start = Instant::now();
+		run(&mut vm).expect("benchmark script runs");
+		fastest = fastest.min(start.elapsed());
+	}
+	let nanos_per_byte = fastest.as_secs_f64() * 1e9 / chunk.code.len() as f64;
+	println!("{name:<13} {} code bytes {:>8.3} ms {nanos_per_byte:>6.2} ns/byte",
+		chunk.code.len(), fastest.as_secs_f64() * 1000.0);
+}
@@ -0,0 +1,51 @@
+//! Compares the stack machine with the register machine, run with `cargo run --release --example backends`
+
+use std::time::Duration;
+use std::time::Instant;
+
+use lox::compiler;
+use lox::compiler::CompilerOptions;
+use lox::debug::Listing;
+use lox::register;
+use lox::register::RegisterVM;
+use lox::verifier::VerifiedChunk;
+use lox::vm::VM;
+
+const TERMS: usize = 50_000;
+const RUNS: usize = 20;
+
+fn main() {
+	let mut source = String::from("0");
+	for i in 0..TERMS {
+		let op = [ "+", "-", "*", "/" ][i % 4];
+		source.push_str(&format!(" {op} -({} + {} * {})", i % 7 + 1, i % 5 + 1, i % 3 + 1));
+	}
+	let options = CompilerOptions { fold_constants: false, peephole: true };
+	let chunk = compiler::compile_with_options(&source, options).expect("benchmark script compiles");
+	let verified_chunk = VerifiedChunk::new(&chunk, 0).expect("benchmark script verifies");
+	let register_chunk = register::translate(&chunk).expect("benchmark script translates");
+
+	let mut vm = VM::<64>::new();
+	vm.set_output(Box::new(std::io::sink()));
+	let stack_time = fastest_run(|| vm.run_verified(verified_chunk).expect("benchmark script runs"));
+	report("stack", Listing::new(&chunk).instruction_count(), stack_time);
+
+	let mut register_vm = RegisterVM::new();
+	register_vm.set_output(Box::new(std::io::sink()));
+	let register_time = fastest_run(|| register_vm.run(&register_chunk));
+	report("register", register_chunk.instruction_count(), register_time);
+}
+
+fn fastest_run(mut run: impl FnMut()) -> Duration {
+	let mut fastest = Duration::MAX;
+	for _ in 0..RUNS {
+		let start = Instant::now();
+		run();
+		fastest = fastest.min(start.elapsed());
+	}
+	fastest
+}
+
+fn report(name: &str, instruction_count: usize, time: Duration) {
+	println!("{name:<9} {instruction_count:>8} instructions {:>8.3} ms", time.as_secs_f64() * 1000.0);
+}
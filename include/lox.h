@@ -0,0 +1,86 @@
+/*
+ * C interface to the lox interpreter.
+ *
+ * Build the shared library with:
+ *   cargo build --lib --release --features capi
+ */
+#ifndef LOX_H
+#define LOX_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LOX_OK 0
+#define LOX_COMPILE_ERROR 1
+#define LOX_RUNTIME_ERROR 2
+#define LOX_BAD_CHUNK 3
+#define LOX_INVALID_ARGUMENT 4
+
+#define LOX_TRACE_OFF 0
+#define LOX_TRACE_OPS 1
+#define LOX_TRACE_OPS_AND_STACK 2
+
+typedef struct LoxVM LoxVM;
+
+/* Native function implemented by the host, returns LOX_OK after writing its result. */
+typedef int (*LoxNativeFn)(const double *args, size_t arg_count, double *result, void *user_data);
+
+/* Receives length bytes written by the VM, the bytes aren't nul terminated. */
+typedef void (*LoxWriteFn)(const char *data, size_t length, void *user_data);
+
+/* Creates a new VM, must be released with lox_vm_free. */
+LoxVM *lox_vm_new(void);
+
+/* Releases a VM created with lox_vm_new, accepts NULL. */
+void lox_vm_free(LoxVM *vm);
+
+/* Discards the transient state of the VM including the last error, registered natives are kept. */
+void lox_vm_reset(LoxVM *vm);
+
+/* Sends program output to function, NULL restores standard output. */
+int lox_set_output(LoxVM *vm, LoxWriteFn function, void *user_data);
+
+/* Sends error reports to function, NULL restores standard error. */
+int lox_set_error_output(LoxVM *vm, LoxWriteFn function, void *user_data);
+
+/* Sends instruction traces to function, NULL restores standard error. */
+int lox_set_trace_output(LoxVM *vm, LoxWriteFn function, void *user_data);
+
+/* Selects how much of the execution is traced, one of the LOX_TRACE_* constants. */
+int lox_set_trace_mode(LoxVM *vm, int trace_mode);
+
+/* Returns the number of values on the VM stack. */
+size_t lox_stack_depth(const LoxVM *vm);
+
+/* Compiles and runs the given source code. */
+int lox_interpret(LoxVM *vm, const char *source);
+
+/* Pushes a number onto the VM stack. */
+int lox_push_number(LoxVM *vm, double number);
+
+/* Pops a number from the VM stack into out. */
+int lox_pop_number(LoxVM *vm, double *out);
+
+/*
+ * Registers a native function, user_data is passed back to every invocation of function.
+ * Lox code can't call natives yet, they are only reachable through lox_call_native.
+ */
+int lox_register_native(LoxVM *vm, const char *name, LoxNativeFn function, void *user_data);
+
+/* Calls a registered native function, writing the result into out. */
+int lox_call_native(LoxVM *vm, const char *name, const double *args, size_t arg_count, double *out);
+
+/*
+ * Returns the last error message owned by the VM, or NULL if there is none.
+ * The string stays valid until the next failing call, lox_vm_reset or lox_vm_free.
+ */
+const char *lox_last_error(const LoxVM *vm);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,314 @@
+//! Assembler reading the text listings written by [crate::debug::Listing] back into chunks
+
+use std::fmt;
+
+use crate::chunk::Chunk;
+use crate::chunk::Columns;
+use crate::op::Op;
+use crate::op::Operand;
+use crate::value::Value;
+
+/// An error found while assembling, with the 1-based line of the listing it occurred on
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct AssembleError {
+
+	pub line: usize,
+
+	pub message: String,
+
+}
+
+impl fmt::Display for AssembleError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Line {}: {}", self.line, self.message)
+	}
+
+}
+
+/// Assembles a listing of `[offset] [line[:start-end] | '|'] MNEMONIC [operand]` lines into a chunk, stopping at the
+/// first error
+pub fn assemble(listing: &str) -> Result<Chunk, AssembleError> {
+	let mut chunk = Chunk::new();
+	let mut line = None;
+	for (index, text) in listing.lines().enumerate() {
+		let text = text.split(';').next().unwrap_or_default();
+		if text.trim().is_empty() {
+			continue;
+		}
+		line = Some(assemble_instruction(&mut chunk, text, line).map_err(|message| AssembleError {
+			line: index + 1,
+			message,
+		})?);
+	}
+	Ok(chunk)
+}
+
+/// Assembles a single instruction and returns its source line
+fn assemble_instruction(chunk: &mut Chunk, text: &str, previous_line: Option<u32>) -> Result<u32, String> {
+	let mut tokens = text.split_whitespace().peekable();
+	let mut prefix = Vec::new();
+	while let Some(token) = tokens.next_if(|token| !token.starts_with("OP_")) {
+		prefix.push(token);
+	}
+	let (line, columns) = match prefix[..] {
+		[] => (previous_line.unwrap_or(1), Columns::default()),
+		[ line ] => parse_line(line, previous_line)?,
+		[ offset, line ] => {
+			if offset.parse::<usize>().ok() != Some(chunk.code.len()) {
+				return Err(format!("Offset '{offset}' doesn't match the instruction offset {}.", chunk.code.len()));
+			}
+			parse_line(line, previous_line)?
+		},
+		_ => return Err(format!("Unexpected '{}'.", prefix[2])),
+	};
+	chunk.set_columns(columns);
+
+	let op = parse_op(tokens.next().ok_or("Expect instruction.")?)?;
+	match op.operand() {
+		Operand::None => chunk.write(op, line),
+		Operand::Constant => {
+			let const_id = add_constant(chunk, tokens.next())?;
+			chunk.write_with_operand(op, const_id, line).map_err(|chunk_error| chunk_error.to_string())?;
+		},
+		Operand::Wide => {
+			let widened_op = parse_op(tokens.next().ok_or("Expect widened instruction.")?)?;
+			if !widened_op.is_widenable() {
+				return Err(format!("Instruction '{}' can't be widened.", widened_op.name()));
+			}
+			let const_id = add_constant(chunk, tokens.next())?;
+			chunk.write_wide(widened_op, const_id, line).map_err(|chunk_error| chunk_error.to_string())?;
+		},
+	}
+	match tokens.next() {
+		Some(token) => Err(format!("Unexpected '{token}'.")),
+		None => Ok(line),
+	}
+}
+
+/// Parses the line of an instruction along with its columns, which are unknown unless given
+fn parse_line(token: &str, previous_line: Option<u32>) -> Result<(u32, Columns), String> {
+	if token == "|" {
+		let line = previous_line.ok_or("Expect a line number on the first instruction.")?;
+		return Ok((line, Columns::default()));
+	}
+	let invalid_line = || format!("Invalid line '{token}'.");
+	let (line, columns) = match token.split_once(':') {
+		Some((line, columns)) => {
+			let (start, end) = columns.split_once('-').ok_or_else(invalid_line)?;
+			let start = start.parse().map_err(|_| invalid_line())?;
+			let end = end.parse().map_err(|_| invalid_line())?;
+			(line, Columns::new(start, end))
+		},
+		None => (token, Columns::default()),
+	};
+	Ok((line.parse().map_err(|_| invalid_line())?, columns))
+}
+
+fn parse_op(token: &str) -> Result<Op, String> {
+	Op::from_name(token).ok_or_else(|| format!("Unknown instruction '{token}'."))
+}
+
+fn add_constant(chunk: &mut Chunk, token: Option<&str>) -> Result<usize, String> {
+	let token = token.ok_or("Expect constant.")?;
+	let unquoted = token.strip_prefix('\'').and_then(|token| token.strip_suffix('\'')).unwrap_or(token);
+	let value = match unquoted {
+		"nil" => Value::nil(),
+		"true" => Value::boolean(true),
+		"false" => Value::boolean(false),
+		_ => Value::new(unquoted.parse::<f64>().map_err(|_| format!("Invalid constant '{token}'."))?),
+	};
+	chunk.add_constant(value).map_err(|chunk_error| chunk_error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::compiler::CompilerOptions;
+	use crate::compiler::compile_with_options;
+	use crate::debug::Listing;
+
+	fn disassemble(chunk: &Chunk) -> String {
+		Listing::new(chunk).to_string()
+	}
+
+	/// xorshift64, deterministic so failures can be reproduced
+	struct Random(u64);
+
+	impl Random {
+
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
+		}
+
+		fn below(&mut self, bound: u64) -> u64 {
+			self.next() % bound
+		}
+
+	}
+
+	fn random_value(random: &mut Random) -> Value {
+		match random.below(3) {
+			0 => Value::new(random.below(1000) as f64),
+			1 => Value::new(random.below(1000) as f64 / -8.0),
+			_ => loop {
+				let value = f64::from_bits(random.next());
+				if !value.is_nan() {
+					break Value::new(value);
+				}
+			},
+		}
+	}
+
+	fn random_chunk(random: &mut Random) -> Chunk {
+		let mut chunk = Chunk::new();
+		let mut line = random.below(3) as u32;
+		for _ in 0..random.below(400) {
+			line += (random.below(4) == 0) as u32;
+			match random.below(8) {
+				0..=2 => {
+					let const_id = chunk.add_constant(random_value(random)).unwrap();
+					chunk.write_with_operand(Op::Constant, const_id, line).unwrap();
+				},
+				3 => {
+					let const_id = chunk.add_constant(random_value(random)).unwrap();
+					chunk.write_wide(Op::Constant, const_id, line).unwrap();
+				},
+				_ => {
+					let simple_ops = [ Op::Add, Op::Subtract, Op::Multiply, Op::Divide, Op::Negate, Op::Return ];
+					chunk.write(simple_ops[random.below(simple_ops.len() as u64) as usize], line);
+				},
+			}
+		}
+		chunk
+	}
+
+	#[test]
+	fn assemble_should_round_trip_with_disassembler() {
+		let mut random = Random(0x2545_f491_4f6c_dd1d);
+		for _ in 0..200 {
+			let chunk = random_chunk(&mut random);
+			let listing = disassemble(&chunk);
+
+			let sut = assemble(&listing).unwrap();
+
+			assert_eq!(sut.code, chunk.code, "{listing}");
+			assert_eq!(sut.line_runs().collect::<Vec<_>>(), chunk.line_runs().collect::<Vec<_>>(), "{listing}");
+			let constant_bits = |chunk: &Chunk| {
+				(0..chunk.constant_count()).map(|const_id| chunk.get_constant(const_id).as_number().map(f64::to_bits)).collect::<Vec<_>>()
+			};
+			assert_eq!(constant_bits(&sut), constant_bits(&chunk), "{listing}");
+			assert_eq!(disassemble(&sut), listing);
+		}
+	}
+
+	#[test]
+	fn assemble_should_accept_hand_written_listing() {
+		let listing = "
+			; (1 + 2) * -3
+			1 OP_CONSTANT 1
+			  OP_CONSTANT '2'
+			  OP_ADD
+			2 OP_CONSTANT 3
+			| OP_NEGATE
+			  OP_MULTIPLY
+			  OP_RETURN
+		";
+
+		let sut = assemble(listing).unwrap();
+
+		assert_eq!(sut.code, [
+			Op::Constant as u8, 0,
+			Op::Constant as u8, 1,
+			Op::Add as u8,
+			Op::Constant as u8, 2,
+			Op::Negate as u8,
+			Op::Multiply as u8,
+			Op::Return as u8,
+		]);
+		assert_eq!(sut.line_runs().collect::<Vec<_>>(), [ (1, 5), (2, 5) ]);
+		assert_eq!(sut.get_constant(1).as_number(), Some(2.0));
+	}
+
+	#[test]
+	fn assemble_should_read_back_constants_of_every_kind() {
+		let mut chunk = Chunk::new();
+		for value in [ Value::nil(), Value::boolean(true), Value::boolean(false), Value::new(-1.5) ] {
+			chunk.write_constant(value, 1).unwrap();
+		}
+		let listing = disassemble(&chunk);
+
+		let sut = assemble(&listing).unwrap();
+
+		assert_eq!(disassemble(&sut), listing);
+		assert_eq!(sut.get_constant(0), &Value::nil());
+		assert_eq!(sut.get_constant(2), &Value::boolean(false));
+	}
+
+	#[test]
+	fn assemble_should_read_back_columns_of_compiled_chunk() {
+		let chunk = compile_with_options("1 +\n  2 * 3", CompilerOptions { fold_constants: false, peephole: false }).unwrap();
+		let listing = disassemble(&chunk);
+
+		let sut = assemble(&listing).unwrap();
+
+		assert_eq!(sut.code, chunk.code);
+		assert_eq!(sut.column_runs().collect::<Vec<_>>(), chunk.column_runs().collect::<Vec<_>>(), "{listing}");
+		assert_eq!(disassemble(&sut), listing);
+	}
+
+	#[test]
+	fn assemble_should_report_invalid_columns() {
+		let result = assemble("0001:4 OP_RETURN");
+
+		assert_eq!(result.err().unwrap().to_string(), "Line 1: Invalid line '0001:4'.");
+	}
+
+	#[test]
+	fn assemble_should_widen_constants_past_one_byte() {
+		let listing = "OP_CONSTANT 0\n".repeat(257);
+
+		let sut = assemble(&listing).unwrap();
+
+		assert_eq!(sut.code[512..], [ Op::Wide as u8, Op::Constant as u8, 0, 1, 0 ]);
+	}
+
+	#[test]
+	fn assemble_should_report_unknown_instruction() {
+		let result = assemble("OP_ADD\nOP_JUMP");
+
+		assert_eq!(result.err().unwrap().to_string(), "Line 2: Unknown instruction 'OP_JUMP'.");
+	}
+
+	#[test]
+	fn assemble_should_report_mismatched_offset() {
+		let result = assemble("0000 0001 OP_ADD\n0002    | OP_ADD");
+
+		assert_eq!(result.err().unwrap().to_string(), "Line 2: Offset '0002' doesn't match the instruction offset 1.");
+	}
+
+	#[test]
+	fn assemble_should_report_missing_constant() {
+		let result = assemble("OP_CONSTANT");
+
+		assert_eq!(result.err().unwrap().to_string(), "Line 1: Expect constant.");
+	}
+
+	#[test]
+	fn assemble_should_report_widened_instruction_without_operand() {
+		let result = assemble("OP_WIDE OP_ADD");
+
+		assert_eq!(result.err().unwrap().to_string(), "Line 1: Instruction 'OP_ADD' can't be widened.");
+	}
+
+	#[test]
+	fn assemble_should_report_trailing_tokens() {
+		let result = assemble("OP_ADD 1");
+
+		assert_eq!(result.err().unwrap().to_string(), "Line 1: Unexpected '1'.");
+	}
+
+}
@@ -0,0 +1,164 @@
+//! On-disk cache of compiled chunks, keyed by a hash of the source text, interpreter version and format version
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::chunk::Chunk;
+use crate::compiler;
+use crate::compiler::CompileError;
+use crate::loxc;
+
+/// Name of the cache directory created next to source files
+pub const CACHE_DIR: &str = ".loxcache";
+
+/// Returns the key a compiled version of the source is cached under
+pub fn source_hash(source: &str) -> u64 {
+	// FNV-1a, unlike std's hashers its output is guaranteed to stay the same across Rust versions
+	const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+	const PRIME: u64 = 0x0000_0100_0000_01b3;
+	let version = format!("{}/{}\0", env!("CARGO_PKG_VERSION"), loxc::FORMAT_VERSION);
+	version.bytes().chain(source.bytes())
+		.fold(OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+}
+
+/// Returns the path the compiled version of the source file is cached at
+pub fn cache_path(source_path: &Path, source: &str) -> PathBuf {
+	let directory = source_path.parent().unwrap_or(Path::new("")).join(CACHE_DIR);
+	let stem = source_path.file_stem().unwrap_or_default().to_string_lossy();
+	directory.join(format!("{stem}-{:016x}.{}", source_hash(source), loxc::EXTENSION))
+}
+
+/// Loads a cached chunk, returns `None` if it isn't cached or the cache entry can't be read
+pub fn load(cache_path: &Path) -> Option<Chunk> {
+	let file = fs::File::open(cache_path).ok()?;
+	loxc::read(&mut io::BufReader::new(file)).ok()
+}
+
+/// Stores a compiled chunk in the cache, replacing entries for older versions of the same source file
+pub fn store(cache_path: &Path, chunk: &Chunk) -> io::Result<()> {
+	let directory = cache_path.parent().unwrap_or(Path::new(""));
+	fs::create_dir_all(directory)?;
+	let mut bytes = Vec::new();
+	loxc::write(&mut bytes, chunk)?;
+	// write to a temporary file first so concurrent runs never see a partially written entry
+	let temp_path = cache_path.with_extension(format!("{}.{}.tmp", loxc::EXTENSION, std::process::id()));
+	fs::write(&temp_path, bytes)?;
+	fs::rename(&temp_path, cache_path)?;
+	remove_stale_entries(cache_path);
+	Ok(())
+}
+
+/// Compiles the source file, using and filling the cache
+pub fn compile(source_path: &Path, source: &str) -> Result<Chunk, Vec<CompileError>> {
+	let cache_path = cache_path(source_path, source);
+	if let Some(chunk) = load(&cache_path) {
+		return Ok(chunk);
+	}
+	let chunk = compiler::compile(source)?;
+	// failing to cache only costs a recompile next time
+	let _ = store(&cache_path, &chunk);
+	Ok(chunk)
+}
+
+/// Removes the entries that were cached for other versions of the same source file
+fn remove_stale_entries(cache_path: &Path) {
+	let (Some(directory), Some(file_name)) = (cache_path.parent(), cache_path.file_name()) else {
+		return;
+	};
+	let file_name = file_name.to_string_lossy();
+	// cache file names are the source file stem and a dash, followed by 16 hex digits and the extension
+	let hash_len = 16 + 1 + loxc::EXTENSION.len();
+	let Some(stem) = file_name.len().checked_sub(hash_len).map(|stem_len| &file_name[..stem_len]) else {
+		return;
+	};
+	let Ok(entries) = fs::read_dir(directory) else {
+		return;
+	};
+	for entry in entries.flatten() {
+		let entry_name = entry.file_name();
+		let entry_name = entry_name.to_string_lossy();
+		let is_stale = entry_name != file_name
+			&& entry_name.len() == file_name.len()
+			&& entry_name.starts_with(stem)
+			&& entry_name.ends_with(loxc::EXTENSION);
+		if is_stale {
+			let _ = fs::remove_file(entry.path());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn temp_dir(name: &str) -> PathBuf {
+		let directory = std::env::temp_dir().join(format!("lox-cache-test-{name}-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&directory);
+		fs::create_dir_all(&directory).unwrap();
+		directory
+	}
+
+	#[test]
+	fn cache_path_should_differ_for_different_sources() {
+		let source_path = Path::new("scripts/main.lox");
+
+		let first = cache_path(source_path, "1 + 2");
+		let second = cache_path(source_path, "1 + 3");
+
+		assert_ne!(first, second);
+		assert!(first.starts_with("scripts/.loxcache"));
+	}
+
+	#[test]
+	fn compile_should_store_and_reuse_compiled_chunk() {
+		let directory = temp_dir("reuse");
+		let source_path = directory.join("main.lox");
+
+		let compiled = compile(&source_path, "1 + 2").unwrap();
+		let cached = load(&cache_path(&source_path, "1 + 2")).unwrap();
+
+		assert_eq!(cached.code, compiled.code);
+		let _ = fs::remove_dir_all(directory);
+	}
+
+	#[test]
+	fn compile_should_not_store_failed_compilations() {
+		let directory = temp_dir("failed");
+		let source_path = directory.join("main.lox");
+
+		let result = compile(&source_path, "1 +");
+
+		assert!(result.is_err());
+		assert!(load(&cache_path(&source_path, "1 +")).is_none());
+		let _ = fs::remove_dir_all(directory);
+	}
+
+	#[test]
+	fn load_should_ignore_corrupted_entries() {
+		let directory = temp_dir("corrupted");
+		let cache_path = cache_path(&directory.join("main.lox"), "1");
+		fs::create_dir_all(cache_path.parent().unwrap()).unwrap();
+		fs::write(&cache_path, b"LOXC garbage").unwrap();
+
+		assert!(load(&cache_path).is_none());
+		let _ = fs::remove_dir_all(directory);
+	}
+
+	#[test]
+	fn store_should_remove_entries_for_older_sources() {
+		let directory = temp_dir("stale");
+		let source_path = directory.join("main.lox");
+		let old_path = cache_path(&source_path, "1");
+		let new_path = cache_path(&source_path, "2");
+
+		store(&old_path, &compiler::compile("1").unwrap()).unwrap();
+		store(&new_path, &compiler::compile("2").unwrap()).unwrap();
+
+		assert!(!old_path.exists());
+		assert!(new_path.exists());
+		let _ = fs::remove_dir_all(directory);
+	}
+
+}
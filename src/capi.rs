@@ -0,0 +1,523 @@
+//! C ABI for embedding the interpreter, declared in `include/lox.h`
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::ffi::c_void;
+use std::io;
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
+
+use crate::value::Value;
+use crate::vm::InterpretError;
+use crate::vm::TraceMode;
+use crate::vm::VM;
+
+pub const LOX_OK: c_int = 0;
+pub const LOX_COMPILE_ERROR: c_int = 1;
+pub const LOX_RUNTIME_ERROR: c_int = 2;
+pub const LOX_BAD_CHUNK: c_int = 3;
+pub const LOX_INVALID_ARGUMENT: c_int = 4;
+
+pub const LOX_TRACE_OFF: c_int = 0;
+pub const LOX_TRACE_OPS: c_int = 1;
+pub const LOX_TRACE_OPS_AND_STACK: c_int = 2;
+
+const CAPI_STACK_SIZE: usize = 256;
+
+/// Native function implemented by the host, returns [LOX_OK] after writing its result
+pub type LoxNativeFn = unsafe extern "C" fn(
+	args: *const f64,
+	arg_count: usize,
+	result: *mut f64,
+	user_data: *mut c_void,
+) -> c_int;
+
+/// Receives `length` bytes written by the VM, the bytes aren't nul terminated
+pub type LoxWriteFn = unsafe extern "C" fn(
+	data: *const c_char,
+	length: usize,
+	user_data: *mut c_void,
+);
+
+/// Sink forwarding everything written to a host callback
+struct CallbackWriter {
+
+	function: LoxWriteFn,
+
+	user_data: *mut c_void,
+
+}
+
+impl Write for CallbackWriter {
+
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// Safety: the host guarantees function and user_data stay valid until the sink is replaced or the VM freed
+		unsafe { (self.function)(buf.as_ptr() as *const c_char, buf.len(), self.user_data) };
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+
+}
+
+/// Wraps the host callback into a sink, null selects the given default stream
+fn writer_from_c(function: Option<LoxWriteFn>, user_data: *mut c_void, default: fn() -> Box<dyn Write>) -> Box<dyn Write> {
+	match function {
+		Some(function) => Box::new(CallbackWriter { function, user_data }),
+		None => default(),
+	}
+}
+
+/// Opaque handle handed out to C hosts
+pub struct LoxVM {
+
+	vm: VM<CAPI_STACK_SIZE>,
+
+	last_error: Option<CString>,
+
+}
+
+impl LoxVM {
+
+	fn set_error(&mut self, message: &str) {
+		// interior nul bytes can't be represented in a C string, so they are dropped
+		self.last_error = CString::new(message.replace('\0', "")).ok();
+	}
+
+	fn set_interpret_error(&mut self, interpret_error: &InterpretError) -> c_int {
+		self.set_error(&interpret_error.to_string());
+		match interpret_error {
+			InterpretError::Compile(_) => LOX_COMPILE_ERROR,
+			InterpretError::Runtime(_) => LOX_RUNTIME_ERROR,
+			InterpretError::BadChunk => LOX_BAD_CHUNK,
+		}
+	}
+
+}
+
+/// Converts a nul terminated C string into a `&str`, returns `None` for null pointers and invalid UTF-8
+///
+/// # Safety
+/// `string` must be null or point to a valid nul terminated string that outlives the returned reference
+unsafe fn str_from_c<'a>(string: *const c_char) -> Option<&'a str> {
+	if string.is_null() {
+		return None;
+	}
+	unsafe { CStr::from_ptr(string) }.to_str().ok()
+}
+
+/// Creates a new VM, must be released with [lox_vm_free]
+#[unsafe(no_mangle)]
+pub extern "C" fn lox_vm_new() -> *mut LoxVM {
+	Box::into_raw(Box::new(LoxVM { vm: VM::new(), last_error: None }))
+}
+
+/// Releases a VM created with [lox_vm_new]
+///
+/// # Safety
+/// `vm` must be null or a pointer returned by [lox_vm_new] that hasn't been freed yet
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_vm_free(vm: *mut LoxVM) {
+	if !vm.is_null() {
+		drop(unsafe { Box::from_raw(vm) });
+	}
+}
+
+/// Discards the transient state of the VM including the last error, registered natives are kept
+///
+/// # Safety
+/// `vm` must be null or a live pointer returned by [lox_vm_new]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_vm_reset(vm: *mut LoxVM) {
+	if let Some(vm) = unsafe { vm.as_mut() } {
+		vm.vm.reset();
+		vm.last_error = None;
+	}
+}
+
+/// Sends program output to `function`, null restores standard output
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new], `function` must remain callable with `user_data`
+/// until the output is replaced or the VM is freed
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_set_output(vm: *mut LoxVM, function: Option<LoxWriteFn>, user_data: *mut c_void) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	vm.vm.set_output(writer_from_c(function, user_data, || Box::new(io::stdout())));
+	LOX_OK
+}
+
+/// Sends error reports to `function`, null restores standard error
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new], `function` must remain callable with `user_data`
+/// until the error output is replaced or the VM is freed
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_set_error_output(vm: *mut LoxVM, function: Option<LoxWriteFn>, user_data: *mut c_void) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	vm.vm.set_error_output(writer_from_c(function, user_data, || Box::new(io::stderr())));
+	LOX_OK
+}
+
+/// Sends instruction traces to `function`, null restores standard error
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new], `function` must remain callable with `user_data`
+/// until the trace output is replaced or the VM is freed
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_set_trace_output(vm: *mut LoxVM, function: Option<LoxWriteFn>, user_data: *mut c_void) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	vm.vm.set_trace_output(writer_from_c(function, user_data, || Box::new(io::stderr())));
+	LOX_OK
+}
+
+/// Selects how much of the execution is traced, one of the `LOX_TRACE_*` constants
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_set_trace_mode(vm: *mut LoxVM, trace_mode: c_int) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	let trace_mode = match trace_mode {
+		LOX_TRACE_OFF => TraceMode::Off,
+		LOX_TRACE_OPS => TraceMode::Ops,
+		LOX_TRACE_OPS_AND_STACK => TraceMode::OpsAndStack,
+		_ => {
+			vm.set_error("Unknown trace mode.");
+			return LOX_INVALID_ARGUMENT;
+		},
+	};
+	vm.vm.set_trace_mode(trace_mode);
+	LOX_OK
+}
+
+/// Returns the number of values on the VM stack
+///
+/// # Safety
+/// `vm` must be null or a live pointer returned by [lox_vm_new]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_stack_depth(vm: *const LoxVM) -> usize {
+	unsafe { vm.as_ref() }.map_or(0, |vm| vm.vm.stack_depth())
+}
+
+/// Compiles and runs the given nul terminated source code
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new], `source` a valid nul terminated string
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_interpret(vm: *mut LoxVM, source: *const c_char) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	let Some(source) = (unsafe { str_from_c(source) }) else {
+		vm.set_error("Source must be valid UTF-8.");
+		return LOX_INVALID_ARGUMENT;
+	};
+	match std::panic::catch_unwind(AssertUnwindSafe(|| crate::interpret(&mut vm.vm, source))) {
+		Ok(Ok(_)) => LOX_OK,
+		Ok(Err(interpret_error)) => vm.set_interpret_error(&interpret_error),
+		Err(_) => {
+			vm.set_error("Interpreter panicked.");
+			LOX_RUNTIME_ERROR
+		},
+	}
+}
+
+/// Pushes a number onto the VM stack
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_push_number(vm: *mut LoxVM, number: f64) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	match vm.vm.push(Value::new(number)) {
+		Ok(_) => LOX_OK,
+		Err(interpret_error) => vm.set_interpret_error(&interpret_error),
+	}
+}
+
+/// Pops a number from the VM stack into `out`
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new], `out` must be writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_pop_number(vm: *mut LoxVM, out: *mut f64) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	if out.is_null() {
+		return LOX_INVALID_ARGUMENT;
+	}
+	match vm.vm.pop() {
+		Ok(value) => match value.as_number() {
+			Some(number) => {
+				unsafe { *out = number; }
+				LOX_OK
+			},
+			None => {
+				vm.set_error("Popped value is not a number.");
+				LOX_RUNTIME_ERROR
+			},
+		},
+		Err(interpret_error) => vm.set_interpret_error(&interpret_error),
+	}
+}
+
+/// Registers a native function, `user_data` is passed back to every invocation of `function`
+///
+/// Lox code can't call natives yet, they are only reachable through [lox_call_native].
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new], `name` a valid nul terminated string and
+/// `function` must remain callable with `user_data` for as long as the VM lives
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_register_native(
+	vm: *mut LoxVM,
+	name: *const c_char,
+	function: Option<LoxNativeFn>,
+	user_data: *mut c_void,
+) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	let (Some(name), Some(function)) = (unsafe { str_from_c(name) }, function) else {
+		vm.set_error("Native name and function must be provided.");
+		return LOX_INVALID_ARGUMENT;
+	};
+	let error_message = format!("Native function '{name}' failed.");
+	vm.vm.define_native(name, Box::new(move |args: &[Value]| {
+		// arguments that aren't numbers are passed as NaN
+		let numbers: Vec<f64> = args.iter().map(|arg| arg.as_number().unwrap_or(f64::NAN)).collect();
+		let mut result = 0.0;
+		// Safety: the host guarantees function and user_data stay valid for the lifetime of the VM
+		match unsafe { function(numbers.as_ptr(), numbers.len(), &mut result, user_data) } {
+			LOX_OK => Ok(Value::new(result)),
+			_ => Err(error_message.clone()),
+		}
+	}));
+	LOX_OK
+}
+
+/// Calls a registered native function with `arg_count` numbers from `args`, writing the result into `out`
+///
+/// # Safety
+/// `vm` must be a live pointer returned by [lox_vm_new], `name` a valid nul terminated string, `args` must
+/// point to `arg_count` readable numbers (or be null when `arg_count` is 0) and `out` must be writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_call_native(
+	vm: *mut LoxVM,
+	name: *const c_char,
+	args: *const f64,
+	arg_count: usize,
+	out: *mut f64,
+) -> c_int {
+	let Some(vm) = (unsafe { vm.as_mut() }) else {
+		return LOX_INVALID_ARGUMENT;
+	};
+	let Some(name) = (unsafe { str_from_c(name) }) else {
+		vm.set_error("Native name must be valid UTF-8.");
+		return LOX_INVALID_ARGUMENT;
+	};
+	if out.is_null() || (args.is_null() && arg_count > 0) {
+		return LOX_INVALID_ARGUMENT;
+	}
+	let args: Vec<Value> = match arg_count {
+		0 => Vec::new(),
+		_ => unsafe { std::slice::from_raw_parts(args, arg_count) }.iter().map(|arg| Value::new(*arg)).collect(),
+	};
+	match vm.vm.call_native(name, &args) {
+		Some(Ok(value)) => match value.as_number() {
+			Some(number) => {
+				unsafe { *out = number; }
+				LOX_OK
+			},
+			None => {
+				vm.set_error(&format!("Native function '{name}' didn't return a number."));
+				LOX_RUNTIME_ERROR
+			},
+		},
+		Some(Err(message)) => {
+			vm.set_error(&message);
+			LOX_RUNTIME_ERROR
+		},
+		None => {
+			vm.set_error(&format!("Undefined native function '{name}'."));
+			LOX_RUNTIME_ERROR
+		},
+	}
+}
+
+/// Returns the message of the last error reported by the VM, or null if there is none
+///
+/// The string is owned by the VM and stays valid until the next failing call, [lox_vm_reset] or [lox_vm_free].
+///
+/// # Safety
+/// `vm` must be null or a live pointer returned by [lox_vm_new]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn lox_last_error(vm: *const LoxVM) -> *const c_char {
+	match unsafe { vm.as_ref() }.and_then(|vm| vm.last_error.as_ref()) {
+		Some(message) => message.as_ptr(),
+		None => std::ptr::null(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	unsafe extern "C" fn add_native(args: *const f64, arg_count: usize, result: *mut f64, user_data: *mut c_void) -> c_int {
+		let args = unsafe { std::slice::from_raw_parts(args, arg_count) };
+		let offset = unsafe { *(user_data as *const f64) };
+		unsafe { *result = args.iter().sum::<f64>() + offset; }
+		LOX_OK
+	}
+
+	unsafe extern "C" fn append_output(data: *const c_char, length: usize, user_data: *mut c_void) {
+		let bytes = unsafe { std::slice::from_raw_parts(data as *const u8, length) };
+		unsafe { &mut *(user_data as *mut Vec<u8>) }.extend_from_slice(bytes);
+	}
+
+	#[test]
+	fn pop_number_should_return_pushed_number() {
+		let vm = lox_vm_new();
+		let mut out = 0.0;
+
+		unsafe {
+			assert_eq!(lox_push_number(vm, 4.5), LOX_OK);
+			assert_eq!(lox_pop_number(vm, &mut out), LOX_OK);
+			lox_vm_free(vm);
+		}
+
+		assert_eq!(out, 4.5);
+	}
+
+	#[test]
+	fn pop_number_should_report_error_on_empty_stack() {
+		let vm = lox_vm_new();
+		let mut out = 0.0;
+
+		unsafe {
+			assert_eq!(lox_pop_number(vm, &mut out), LOX_RUNTIME_ERROR);
+			assert_eq!(CStr::from_ptr(lox_last_error(vm)).to_str().unwrap(), "Stack underflow.");
+			lox_vm_free(vm);
+		}
+	}
+
+	#[test]
+	fn vm_reset_should_clear_stack_and_error() {
+		let vm = lox_vm_new();
+		let mut out = 0.0;
+
+		unsafe {
+			lox_push_number(vm, 1.0);
+			lox_pop_number(vm, &mut out);
+			lox_pop_number(vm, &mut out);
+			lox_push_number(vm, 2.0);
+			lox_vm_reset(vm);
+			assert_eq!(lox_stack_depth(vm), 0);
+			assert!(lox_last_error(vm).is_null());
+			lox_vm_free(vm);
+		}
+	}
+
+	#[test]
+	fn call_native_should_invoke_registered_callback_with_user_data() {
+		let vm = lox_vm_new();
+		let mut offset = 10.0;
+		let args = [1.0, 2.0];
+		let mut out = 0.0;
+
+		unsafe {
+			let user_data = &mut offset as *mut f64 as *mut c_void;
+			assert_eq!(lox_register_native(vm, c"sum".as_ptr(), Some(add_native), user_data), LOX_OK);
+			assert_eq!(lox_call_native(vm, c"sum".as_ptr(), args.as_ptr(), args.len(), &mut out), LOX_OK);
+			lox_vm_free(vm);
+		}
+
+		assert_eq!(out, 13.0);
+	}
+
+	#[test]
+	fn call_native_should_report_undefined_native() {
+		let vm = lox_vm_new();
+		let mut out = 0.0;
+
+		unsafe {
+			assert_eq!(lox_call_native(vm, c"missing".as_ptr(), std::ptr::null(), 0, &mut out), LOX_RUNTIME_ERROR);
+			assert_eq!(CStr::from_ptr(lox_last_error(vm)).to_str().unwrap(), "Undefined native function 'missing'.");
+			lox_vm_free(vm);
+		}
+	}
+
+	#[test]
+	fn interpret_should_write_to_callback_sinks() {
+		let vm = lox_vm_new();
+		let mut output: Vec<u8> = Vec::new();
+		let mut error_output: Vec<u8> = Vec::new();
+		let mut trace_output: Vec<u8> = Vec::new();
+
+		unsafe {
+			lox_set_output(vm, Some(append_output), &mut output as *mut Vec<u8> as *mut c_void);
+			lox_set_error_output(vm, Some(append_output), &mut error_output as *mut Vec<u8> as *mut c_void);
+			lox_set_trace_output(vm, Some(append_output), &mut trace_output as *mut Vec<u8> as *mut c_void);
+			lox_set_trace_mode(vm, LOX_TRACE_OPS);
+			lox_interpret(vm, c"1 + 2".as_ptr());
+			lox_interpret(vm, c"1 +".as_ptr());
+			lox_vm_free(vm);
+		}
+
+		assert_eq!(String::from_utf8(output).unwrap(), "3\n");
+		assert_eq!(String::from_utf8(error_output).unwrap(), "[line 1] Error at end: Expect expression.\n");
+		assert!(String::from_utf8(trace_output).unwrap().contains("OP_RETURN"));
+	}
+
+	#[test]
+	fn interpret_should_keep_compile_errors_as_last_error() {
+		let vm = lox_vm_new();
+		let mut error_output: Vec<u8> = Vec::new();
+
+		unsafe {
+			lox_set_error_output(vm, Some(append_output), &mut error_output as *mut Vec<u8> as *mut c_void);
+			assert_eq!(lox_interpret(vm, c"(1 +\n2".as_ptr()), LOX_COMPILE_ERROR);
+			assert_eq!(CStr::from_ptr(lox_last_error(vm)).to_str().unwrap(), "[line 2] Error at end: Expect ')' after expression.");
+			lox_vm_free(vm);
+		}
+	}
+
+	#[test]
+	fn set_trace_mode_should_reject_unknown_mode() {
+		let vm = lox_vm_new();
+
+		unsafe {
+			assert_eq!(lox_set_trace_mode(vm, 3), LOX_INVALID_ARGUMENT);
+			assert_eq!(CStr::from_ptr(lox_last_error(vm)).to_str().unwrap(), "Unknown trace mode.");
+			lox_vm_free(vm);
+		}
+	}
+
+	#[test]
+	fn functions_should_reject_null_vm() {
+		let mut out = 0.0;
+
+		unsafe {
+			assert_eq!(lox_interpret(std::ptr::null_mut(), c"".as_ptr()), LOX_INVALID_ARGUMENT);
+			assert_eq!(lox_pop_number(std::ptr::null_mut(), &mut out), LOX_INVALID_ARGUMENT);
+			assert!(lox_last_error(std::ptr::null()).is_null());
+		}
+	}
+
+}
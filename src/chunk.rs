@@ -1,18 +1,118 @@
+use std::fmt;
 use std::ops::Range;
 
+use crate::rle::ColumnTable;
+use crate::rle::MemoryUsage;
 use crate::rle::RunLengthEncoder;
-use crate::op::*;
+use crate::op::MAX_OPERAND;
+use crate::op::Op;
+use crate::op::WIDE_OPERAND_SIZE;
 use crate::value::Value;
 use crate::value::ValueArray;
 
-const MAX_CONSTANTS: usize = 0xffff_ffff_ffff;
+/// Constant indices are operands, so the constant table can't outgrow the largest operand
+const MAX_CONSTANTS: usize = MAX_OPERAND + 1;
 
+/// Limits that were exceeded while writing a chunk, reported as compile errors
+#[derive(PartialEq)] #[derive(Debug)]
+pub enum ChunkError {
+
+	TooManyConstants,
+
+	/// Occurs when an operand doesn't fit even with the [Op::Wide] prefix
+	OperandTooLarge,
+
+	/// Occurs when writing an operand for an instruction that doesn't take one
+	NoOperand,
+
+}
+
+impl fmt::Display for ChunkError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::TooManyConstants => write!(f, "Too many constants in one chunk."),
+			Self::OperandTooLarge => write!(f, "Operand too large."),
+			Self::NoOperand => write!(f, "Instruction doesn't take an operand."),
+		}
+	}
+
+}
+
+/// Range of byte columns on a source line, 1-based with an exclusive end, `0..0` when unknown
+///
+/// Columns take two bytes each to keep the column table small, columns past [u16::MAX] are clamped.
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)] #[derive(Default)]
+pub struct Columns {
+
+	pub start: u16,
+
+	pub end: u16,
+
+}
+
+impl Columns {
+
+	pub fn new(start: usize, end: usize) -> Self {
+		Self { start: start.min(u16::MAX as usize) as u16, end: end.min(u16::MAX as usize) as u16 }
+	}
+
+	pub fn is_known(&self) -> bool {
+		self.start > 0
+	}
+
+	/// Returns the smallest range covering both, unknown columns are ignored
+	pub fn union(self, other: Self) -> Self {
+		match (self.is_known(), other.is_known()) {
+			(true, true) => Self { start: self.start.min(other.start), end: self.end.max(other.end) },
+			(false, _) => other,
+			(true, false) => self,
+		}
+	}
+
+}
+
+impl fmt::Display for Columns {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}-{}", self.start, self.end)
+	}
+
+}
+
+/// Where in the source the instruction at a code offset was compiled from
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)] #[derive(Default)]
+pub struct Span {
+
+	/// Id of the source file, as set with [Chunk::set_file]
+	pub file: u32,
+
+	pub line: u32,
+
+	pub columns: Columns,
+
+}
+
+/// Compiled code with its constants and a source map
+///
+/// The source map consists of two run-length encoded tables: one with the line of every code byte and one with
+/// its columns. Lines change rarely, so keeping the columns apart lets the line table stay a few runs long while
+/// the column table takes a run per instruction at most, which is why its runs are packed into a few bytes each.
+/// A chunk comes from a single source file, which is recorded once for the whole chunk.
+#[derive(Clone)]
 pub struct Chunk {
 
 	pub code: Vec<u8>,
 
 	lines: RunLengthEncoder<u32>,
 
+	columns: ColumnTable,
+
+	/// Columns recorded for the code bytes written next
+	current_columns: Columns,
+
+	file: u32,
+
 	constants: ValueArray,
 
 }
@@ -23,6 +123,9 @@ impl Chunk {
 		Chunk {
 			code: Vec::with_capacity(8),
 			lines: RunLengthEncoder::<u32>::new(),
+			columns: ColumnTable::new(),
+			current_columns: Columns::default(),
+			file: 0,
 			constants: ValueArray::new(),
 		}
 	}
@@ -37,36 +140,131 @@ impl Chunk {
 		}
 	}
 
+	/// Returns the line table as runs of line numbers and the number of code bytes they cover
+	pub fn line_runs(&self) -> impl Iterator<Item = (u32, u32)> {
+		self.lines.runs().map(|(line, length)| (*line, length))
+	}
+
+	/// Returns the number of runs in the line table and the memory they take
+	pub fn line_table_usage(&self) -> MemoryUsage {
+		self.lines.memory_usage()
+	}
+
+	/// Returns the column table as runs of columns and the number of code bytes they cover
+	pub fn column_runs(&self) -> impl Iterator<Item = (Columns, u32)> {
+		self.columns.runs()
+	}
+
+	/// Returns the number of runs in the column table and the memory they take
+	pub fn column_table_usage(&self) -> MemoryUsage {
+		self.columns.memory_usage()
+	}
+
 	pub fn find_line(&self, offset: usize) -> Option<u32> {
-		match self.lines.find(offset) {
-			Some(v) => Some(*v),
-			None => None
-		}
+		self.lines.find(offset).copied()
+	}
+
+	/// Returns the columns of the code byte at the given offset, unknown if the column table doesn't cover it
+	pub fn find_columns(&self, offset: usize) -> Columns {
+		self.columns.find(offset).unwrap_or_default()
+	}
+
+	/// Returns the source location of the code byte at the given offset, `None` if the line table doesn't cover it
+	pub fn find_span(&self, offset: usize) -> Option<Span> {
+		let line = self.find_line(offset)?;
+		Some(Span { file: self.file, line, columns: self.find_columns(offset) })
+	}
+
+	pub fn file(&self) -> u32 {
+		self.file
+	}
+
+	/// Sets the id of the source file the chunk was compiled from, ids are assigned by the embedder and 0 by default
+	pub fn set_file(&mut self, file: u32) {
+		self.file = file;
+	}
+
+	/// Sets the columns recorded for the code written from now on, the line is given with every write instead as
+	/// writing code without columns is common
+	pub fn set_columns(&mut self, columns: Columns) {
+		self.current_columns = columns;
 	}
 
-	pub fn write(&mut self, byte: u8, line: u32) {
-		self.code.push(byte);
+	pub fn write(&mut self, byte: impl Into<u8>, line: u32) {
+		self.code.push(byte.into());
 		self.lines.add(line);
+		self.columns.add(self.current_columns);
 	}
 
-	/// Writes a constant value into the chunk: either [Op::Constant] followed by a one byte index or
-	/// [Op::ConstantLong] followed by a three byte index
-	pub fn write_constant(&mut self, value: Value, line: u32) {
-		self.constants.write(value);
-		let const_index = self.constants.values.len() - 1;
-		if const_index > MAX_CONSTANTS {
-			panic!("Cannot write constant to chunk, maximum reached");
+	/// Writes an instruction with its operand: a one byte operand if it fits, otherwise the instruction is
+	/// prefixed with [Op::Wide] and followed by a three byte operand
+	pub fn write_with_operand(&mut self, op: Op, operand: usize, line: u32) -> Result<(), ChunkError> {
+		if !op.is_widenable() {
+			return Err(ChunkError::NoOperand);
+		}
+		if operand > MAX_OPERAND {
+			return Err(ChunkError::OperandTooLarge);
+		}
+		if operand <= u8::MAX as usize {
+			self.write(op, line);
+			self.write(operand as u8, line);
+			return Ok(());
 		}
-		if const_index < u8::MAX as usize {
-			self.write(OP_CONSTANT, line);
-			self.write(const_index as u8, line);
-			return;
+		self.write_wide(op, operand, line)
+	}
+
+	/// Writes an instruction with its operand always prefixed by [Op::Wide], even if the operand fits into a byte
+	pub fn write_wide(&mut self, op: Op, operand: usize, line: u32) -> Result<(), ChunkError> {
+		if !op.is_widenable() {
+			return Err(ChunkError::NoOperand);
 		}
-		self.write(OP_CONSTANT_LONG, line);
-		let bytes = const_index.to_be_bytes();
-		for i in 1..4 {
-			self.write(bytes[bytes.len() - i], line);
+		if operand > MAX_OPERAND {
+			return Err(ChunkError::OperandTooLarge);
 		}
+		self.write(Op::Wide, line);
+		self.write(op, line);
+		let bytes = operand.to_be_bytes();
+		for byte in &bytes[bytes.len() - WIDE_OPERAND_SIZE..] {
+			self.write(*byte, line);
+		}
+		Ok(())
+	}
+
+	/// Shortens the code to `len` bytes, dropping the source map of the removed bytes
+	pub fn truncate(&mut self, len: usize) {
+		self.code.truncate(len);
+		self.lines.truncate(len);
+		self.columns.truncate(len);
+	}
+
+	/// Shortens the constant table to `count` values
+	pub fn truncate_constants(&mut self, count: usize) {
+		self.constants.values.truncate(count);
+	}
+
+	/// Adds a value to the constant table and returns its index
+	pub fn add_constant(&mut self, value: Value) -> Result<usize, ChunkError> {
+		if self.constants.values.len() >= MAX_CONSTANTS {
+			return Err(ChunkError::TooManyConstants);
+		}
+		self.constants.write(value);
+		Ok(self.constants.values.len() - 1)
+	}
+
+	/// Adds a constant value to the chunk and writes the [Op::Constant] instruction loading it
+	pub fn write_constant(&mut self, value: Value, line: u32) -> Result<(), ChunkError> {
+		let const_index = self.add_constant(value)?;
+		self.write_with_operand(Op::Constant, const_index, line)
+	}
+
+	/// Returns the number of values in the constant table
+	pub fn constant_count(&self) -> usize {
+		self.constants.values.len()
+	}
+
+	/// Returns the constant associated with the given "constant id", or `None` if it doesn't exist
+	pub fn find_constant(&self, const_id: usize) -> Option<&Value> {
+		self.constants.values.get(const_id)
 	}
 
 	/// Returns the constant associated with the given "constant id", panics if it doesn't exist
@@ -76,24 +274,62 @@ impl Chunk {
 
 }
 
+impl Default for Chunk {
+
+	fn default() -> Self {
+		Self::new()
+	}
+
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 
 	#[test]
-	fn write_constant_should_use_op_constant_long_when_exceeding_short_limit() {
-		let short_limit = u8::MAX as usize;
+	fn write_constant_should_use_op_wide_when_exceeding_short_limit() {
+		let short_limit = u8::MAX as usize + 1;
 		let mut chunk = Chunk::new();
 
 		for i in 0..(short_limit + 1) {
-			chunk.write_constant(Value::new(1.0), i as u32);
+			chunk.write_constant(Value::new(1.0), i as u32).unwrap();
 		}
 
-		// result should be 255 OP_CONSTANT's followed by 1 byte indices (510 bytes total)
-		// then a single OP_CONSTANT_LONG followed by a 3 byte index (4 bytes addition)
-		assert_eq!(chunk.code.len(), (short_limit * op_size(OP_CONSTANT)) + op_size(OP_CONSTANT_LONG));
+		// result should be 256 OP_CONSTANT's followed by 1 byte indices (512 bytes total)
+		// then a single OP_WIDE prefixing an OP_CONSTANT with a 3 byte index (5 bytes addition)
+		assert_eq!(chunk.code.len(), (short_limit * Op::Constant.size()) + Op::Wide.size());
 		assert_eq!(chunk.constants.values.len(), short_limit + 1);
-		assert_eq!(chunk.code[chunk.code.len() - 4], OP_CONSTANT_LONG);
+		assert_eq!(chunk.code[chunk.code.len() - 5..], [ Op::Wide as u8, Op::Constant as u8, 0, 1, 0 ]);
+	}
+
+	#[test]
+	fn add_constant_should_error_when_constant_table_is_full() {
+		let mut chunk = Chunk::new();
+		chunk.constants.values = vec![Value::new(0.0);MAX_CONSTANTS];
+
+		let result = chunk.write_constant(Value::new(1.0), 1);
+
+		assert_eq!(result, Err(ChunkError::TooManyConstants));
+		assert_eq!(ChunkError::TooManyConstants.to_string(), "Too many constants in one chunk.");
+		assert!(chunk.code.is_empty());
+	}
+
+	#[test]
+	fn write_with_operand_should_error_when_operand_exceeds_wide_limit() {
+		let mut chunk = Chunk::new();
+
+		let result = chunk.write_with_operand(Op::Constant, MAX_OPERAND + 1, 1);
+
+		assert_eq!(result, Err(ChunkError::OperandTooLarge));
+	}
+
+	#[test]
+	fn write_with_operand_should_error_when_instruction_has_no_operand() {
+		let mut chunk = Chunk::new();
+
+		let result = chunk.write_with_operand(Op::Add, 1, 1);
+
+		assert_eq!(result, Err(ChunkError::NoOperand));
 	}
 
 	#[test]
@@ -119,4 +355,38 @@ mod tests {
 		assert!(chunk.find_line(0).is_none());
 	}
 
+	#[test]
+	fn find_span_should_combine_file_line_and_columns() {
+		let mut chunk = Chunk::new();
+		chunk.set_file(3);
+		chunk.write(Op::Negate, 1);
+		chunk.set_columns(Columns::new(5, 7));
+		chunk.write(Op::Negate, 2);
+
+		assert_eq!(chunk.find_span(0), Some(Span { file: 3, line: 1, columns: Columns::default() }));
+		assert_eq!(chunk.find_span(1), Some(Span { file: 3, line: 2, columns: Columns::new(5, 7) }));
+		assert_eq!(chunk.find_span(2), None);
+	}
+
+	#[test]
+	fn write_should_share_column_runs_between_bytes_of_an_instruction() {
+		let mut chunk = Chunk::new();
+		chunk.set_columns(Columns::new(1, 4));
+
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.set_columns(Columns::new(5, 6));
+		chunk.write(Op::Negate, 1);
+
+		assert_eq!(chunk.column_runs().collect::<Vec<_>>(), [ (Columns::new(1, 4), 2), (Columns::new(5, 6), 1) ]);
+	}
+
+	#[test]
+	fn union_should_ignore_unknown_columns() {
+		let sut = Columns::new(5, 7);
+
+		assert_eq!(sut.union(Columns::new(1, 2)), Columns::new(1, 7));
+		assert_eq!(sut.union(Columns::default()), sut);
+		assert_eq!(Columns::default().union(sut), sut);
+	}
+
 }
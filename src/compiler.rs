@@ -1,5 +1,540 @@
+use std::fmt;
+
+use crate::chunk::Chunk;
+use crate::chunk::Columns;
+use crate::debug::Instruction;
+use crate::op::Op;
+use crate::peephole;
+use crate::peephole::Report;
 use crate::scanner::Scanner;
+use crate::scanner::Token;
+use crate::scanner::TokenKind;
+use crate::value::Value;
+
+/// An error found while compiling, formatted like `[line 1] Error at '+': Expect expression.`
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct CompileError {
+
+	pub line: u32,
+
+	/// Where on the line the error occurred, eg. ` at '+'` or ` at end`
+	pub location: String,
+
+	pub message: String,
+
+}
+
+impl fmt::Display for CompileError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "[line {}] Error{}: {}", self.line, self.location, self.message)
+	}
+
+}
+
+/// Switches for the optimizations done while compiling, all enabled by default
+#[derive(Clone)] #[derive(Debug)]
+pub struct CompilerOptions {
+
+	/// Evaluates operations on constant operands at compile time, eg. `60 * 60` compiles to a single constant
+	pub fold_constants: bool,
+
+	/// Runs the [peephole] optimizer over the finished chunk
+	pub peephole: bool,
+
+}
+
+impl Default for CompilerOptions {
+
+	fn default() -> Self {
+		Self { fold_constants: true, peephole: true }
+	}
+
+}
+
+/// Operator precedences from lowest to highest
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(PartialOrd)]
+enum Precedence {
+	None,
+	Assignment,
+	Or,
+	And,
+	Equality,
+	Comparison,
+	Term,
+	Factor,
+	Unary,
+	Call,
+	Primary,
+}
+
+impl Precedence {
+
+	/// Returns the next higher precedence, used to make binary operators left-associative
+	fn next(self) -> Self {
+		match self {
+			Self::None => Self::Assignment,
+			Self::Assignment => Self::Or,
+			Self::Or => Self::And,
+			Self::And => Self::Equality,
+			Self::Equality => Self::Comparison,
+			Self::Comparison => Self::Term,
+			Self::Term => Self::Factor,
+			Self::Factor => Self::Unary,
+			Self::Unary => Self::Call,
+			Self::Call | Self::Primary => Self::Primary,
+		}
+	}
+
+}
+
+type ParseFn<'a> = fn(&mut Parser<'a>);
+
+/// Parse functions for a token kind when it starts an expression (prefix) or follows one (infix)
+struct ParseRule<'a> {
+
+	prefix: Option<ParseFn<'a>>,
+
+	infix: Option<ParseFn<'a>>,
+
+	precedence: Precedence,
+
+}
+
+fn get_rule<'a>(kind: TokenKind) -> ParseRule<'a> {
+	let (prefix, infix, precedence): (Option<ParseFn<'a>>, Option<ParseFn<'a>>, Precedence) = match kind {
+		TokenKind::LeftParen => (Some(Parser::grouping), None, Precedence::None),
+		TokenKind::Minus => (Some(Parser::unary), Some(Parser::binary), Precedence::Term),
+		TokenKind::Plus => (None, Some(Parser::binary), Precedence::Term),
+		TokenKind::Slash | TokenKind::Star => (None, Some(Parser::binary), Precedence::Factor),
+		TokenKind::Number => (Some(Parser::number), None, Precedence::None),
+		_ => (None, None, Precedence::None),
+	};
+	ParseRule { prefix, infix, precedence }
+}
+
+/// Deepest the parser recurses into nested expressions, deeper nesting is a compile error rather than a native stack
+/// overflow, which can't be recovered from
+const MAX_NESTING_DEPTH: usize = 256;
+
+struct Parser<'a> {
+
+	scanner: Scanner<'a>,
+
+	current: Token<'a>,
+
+	previous: Token<'a>,
+
+	chunk: Chunk,
+
+	/// Code offsets of the instructions emitted so far, used to find the operands of an instruction
+	instruction_offsets: Vec<usize>,
+
+	options: CompilerOptions,
+
+	/// Number of [Parser::parse_precedence] calls currently running
+	nesting_depth: usize,
+
+	errors: Vec<CompileError>,
+
+	/// Set after an error to suppress the cascade of errors that usually follows it
+	panic_mode: bool,
+
+}
+
+impl<'a> Parser<'a> {
+
+	fn new(source: &'a str, options: CompilerOptions) -> Self {
+		let start = Token { kind: TokenKind::Eof, content: "", line: 1, column: 0 };
+		Self {
+			scanner: Scanner::new(source),
+			current: start,
+			previous: start,
+			chunk: Chunk::new(),
+			instruction_offsets: Vec::new(),
+			options,
+			nesting_depth: 0,
+			errors: Vec::new(),
+			panic_mode: false,
+		}
+	}
+
+	fn advance(&mut self) {
+		self.previous = self.current;
+		loop {
+			let line = self.previous.line;
+			self.current = self.scanner.next().unwrap_or(Token { kind: TokenKind::Eof, content: "", line, column: 0 });
+			if self.current.kind != TokenKind::Error {
+				break;
+			}
+			self.error_at_current(self.current.content);
+		}
+	}
+
+	fn consume(&mut self, kind: TokenKind, message: &str) {
+		if self.current.kind == kind {
+			self.advance();
+			return;
+		}
+		self.error_at_current(message);
+	}
+
+	fn expression(&mut self) {
+		self.parse_precedence(Precedence::Assignment);
+	}
+
+	fn parse_precedence(&mut self, precedence: Precedence) {
+		if self.nesting_depth == MAX_NESTING_DEPTH {
+			self.error_at_current("Expression nests too deeply.");
+			return;
+		}
+		self.nesting_depth += 1;
+		self.parse_precedence_nested(precedence);
+		self.nesting_depth -= 1;
+	}
+
+	fn parse_precedence_nested(&mut self, precedence: Precedence) {
+		self.advance();
+		let Some(prefix_rule) = get_rule(self.previous.kind).prefix else {
+			self.error("Expect expression.");
+			return;
+		};
+		prefix_rule(self);
+		while precedence <= get_rule(self.current.kind).precedence {
+			self.advance();
+			if let Some(infix_rule) = get_rule(self.previous.kind).infix {
+				infix_rule(self);
+			}
+		}
+	}
+
+	fn number(&mut self) {
+		// the scanner only produces digits with an optional fraction, which always parse
+		let value = self.previous.content.parse::<f64>().unwrap_or(f64::NAN);
+		self.emit_constant(Value::new(value), self.previous);
+	}
+
+	fn grouping(&mut self) {
+		self.expression();
+		self.consume(TokenKind::RightParen, "Expect ')' after expression.");
+	}
+
+	fn unary(&mut self) {
+		let operator = self.previous;
+		self.parse_precedence(Precedence::Unary);
+		if operator.kind == TokenKind::Minus {
+			self.emit(Op::Negate, operator);
+		}
+	}
+
+	fn binary(&mut self) {
+		let operator = self.previous;
+		self.parse_precedence(get_rule(operator.kind).precedence.next());
+		match operator.kind {
+			TokenKind::Plus => self.emit(Op::Add, operator),
+			TokenKind::Minus => self.emit(Op::Subtract, operator),
+			TokenKind::Star => self.emit(Op::Multiply, operator),
+			TokenKind::Slash => self.emit(Op::Divide, operator),
+			_ => {}
+		}
+	}
+
+	/// Emits an instruction located at the token, folding it with its operands into a constant when they are all
+	/// constants
+	fn emit(&mut self, op: Op, token: Token) {
+		if self.options.fold_constants && self.fold_constants(op, token) {
+			return;
+		}
+		self.instruction_offsets.push(self.chunk.code.len());
+		self.chunk.set_columns(token_columns(&token));
+		self.chunk.write(op, token.line);
+	}
+
+	fn emit_constant(&mut self, value: Value, token: Token) {
+		self.chunk.set_columns(token_columns(&token));
+		self.write_constant(value, token.line);
+	}
+
+	/// Writes a constant load at the columns set last
+	fn write_constant(&mut self, value: Value, line: u32) {
+		self.instruction_offsets.push(self.chunk.code.len());
+		if let Err(chunk_error) = self.chunk.write_constant(value, line) {
+			self.error(&chunk_error.to_string());
+		}
+	}
+
+	/// Replaces the constants loaded as operands of the operation with the constant result, returns whether the
+	/// operation could be folded
+	fn fold_constants(&mut self, op: Op, operator: Token) -> bool {
+		let (operand_count, _) = op.stack_effect();
+		let Some(first_operand) = self.instruction_offsets.len().checked_sub(operand_count) else {
+			return false;
+		};
+		let mut operands = Vec::with_capacity(operand_count);
+		for offset in &self.instruction_offsets[first_operand..] {
+			match Instruction::decode(&self.chunk, *offset) {
+				Some(Instruction { operand: Some(const_id), constant: Some(value), opcode, widened_opcode, .. })
+					if opcode == Op::Constant as u8 || widened_opcode == Some(Op::Constant as u8) => {
+					operands.push((const_id, value));
+				},
+				_ => return false,
+			}
+		}
+		// division by zero and NaN operands follow IEEE 754 like at runtime, as the same operations are used
+		let result = match (op, &operands[..]) {
+			(Op::Negate, [ (_, operand) ]) => fold(*operand, Value::negate),
+			(Op::Add, [ (_, left), (_, right) ]) => fold(*left, |value| value.add(right)),
+			(Op::Subtract, [ (_, left), (_, right) ]) => fold(*left, |value| value.subtract(right)),
+			(Op::Multiply, [ (_, left), (_, right) ]) => fold(*left, |value| value.multiply(right)),
+			(Op::Divide, [ (_, left), (_, right) ]) => fold(*left, |value| value.divide(right)),
+			_ => return false,
+		};
+
+		// the folded constant takes the line of its first operand and the columns of everything folded on that line
+		let code_start = self.instruction_offsets[first_operand];
+		let line = self.chunk.find_line(code_start).unwrap_or(self.previous.line);
+		let columns = self.instruction_offsets[first_operand..].iter()
+			.filter_map(|offset| self.chunk.find_span(*offset))
+			.map(|span| (span.line, span.columns))
+			.chain([ (operator.line, token_columns(&operator)) ])
+			.filter(|(span_line, _)| *span_line == line)
+			.fold(Columns::default(), |columns, (_, span_columns)| columns.union(span_columns));
+		self.instruction_offsets.truncate(first_operand);
+		self.chunk.truncate(code_start);
+		// operand constants are the last ones added, unless the constant table overflowed
+		let first_const_id = operands.iter().map(|(const_id, _)| *const_id).min().unwrap_or(usize::MAX);
+		if first_const_id + operands.len() == self.chunk.constant_count() {
+			self.chunk.truncate_constants(first_const_id);
+		}
+		self.chunk.set_columns(columns);
+		self.write_constant(result, line);
+		true
+	}
+
+	fn error_at_current(&mut self, message: &str) {
+		self.error_at(self.current, message);
+	}
+
+	fn error(&mut self, message: &str) {
+		self.error_at(self.previous, message);
+	}
+
+	fn error_at(&mut self, token: Token, message: &str) {
+		if self.panic_mode {
+			return;
+		}
+		self.panic_mode = true;
+		let location = match token.kind {
+			TokenKind::Eof => " at end".to_string(),
+			TokenKind::Error => String::new(),
+			_ => format!(" at '{}'", token.content),
+		};
+		self.errors.push(CompileError { line: token.line, location, message: message.to_string() });
+	}
+
+}
+
+/// Returns the columns the token covers, tokens that weren't read from the source have unknown columns
+fn token_columns(token: &Token) -> Columns {
+	match token.column {
+		0 => Columns::default(),
+		column => Columns::new(column as usize, column as usize + token.content.len()),
+	}
+}
+
+/// Applies an in-place operation to a copy of the value
+fn fold(mut value: Value, operation: impl FnOnce(&mut Value)) -> Value {
+	operation(&mut value);
+	value
+}
+
+/// Compiles the source into a chunk with the default options, or returns every error found
+pub fn compile(source: &str) -> Result<Chunk, Vec<CompileError>> {
+	compile_with_options(source, CompilerOptions::default())
+}
+
+/// Compiles the source into a chunk, or returns every error found
+pub fn compile_with_options(source: &str, options: CompilerOptions) -> Result<Chunk, Vec<CompileError>> {
+	compile_with_report(source, options).map(|(chunk, _)| chunk)
+}
+
+/// Compiles the source like [compile_with_options], also returning the report of the [peephole] optimizer if it ran
+pub fn compile_with_report(source: &str, options: CompilerOptions) -> Result<(Chunk, Option<Report>), Vec<CompileError>> {
+	let mut parser = Parser::new(source, options);
+	parser.advance();
+	parser.expression();
+	parser.consume(TokenKind::Eof, "Expect end of expression.");
+	let end = parser.previous;
+	parser.emit(Op::Return, end);
+	if !parser.errors.is_empty() {
+		return Err(parser.errors);
+	}
+	match parser.options.peephole {
+		true => {
+			let (chunk, report) = peephole::optimize(&parser.chunk);
+			Ok((chunk, Some(report)))
+		},
+		false => Ok((parser.chunk, None)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn compile_unfolded(source: &str) -> Chunk {
+		compile_with_options(source, CompilerOptions { fold_constants: false, peephole: false }).unwrap()
+	}
+
+	#[test]
+	fn compile_should_respect_precedence() {
+		let chunk = compile_unfolded("1 + 2 * 3");
+
+		assert_eq!(chunk.code, [
+			Op::Constant as u8, 0,
+			Op::Constant as u8, 1,
+			Op::Constant as u8, 2,
+			Op::Multiply as u8,
+			Op::Add as u8,
+			Op::Return as u8,
+		]);
+	}
+
+	#[test]
+	fn compile_should_make_binary_operators_left_associative() {
+		let chunk = compile_unfolded("1 - 2 - 3");
+
+		assert_eq!(chunk.code, [
+			Op::Constant as u8, 0,
+			Op::Constant as u8, 1,
+			Op::Subtract as u8,
+			Op::Constant as u8, 2,
+			Op::Subtract as u8,
+			Op::Return as u8,
+		]);
+	}
+
+	#[test]
+	fn compile_should_compile_grouping_and_negation() {
+		let chunk = compile_unfolded("-(1 + 2)");
+
+		assert_eq!(chunk.code, [
+			Op::Constant as u8, 0,
+			Op::Constant as u8, 1,
+			Op::Add as u8,
+			Op::Negate as u8,
+			Op::Return as u8,
+		]);
+	}
+
+	#[test]
+	fn compile_should_track_lines() {
+		let chunk = compile_unfolded("1 +\n2");
+
+		assert_eq!(chunk.find_line(0), Some(1));
+		assert_eq!(chunk.find_line(2), Some(2));
+	}
+
+	#[test]
+	fn compile_should_report_missing_expression() {
+		let errors = compile("1 +").err().unwrap();
+
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect expression.");
+	}
+
+	#[test]
+	fn compile_should_report_unclosed_grouping() {
+		let errors = compile("(1 + 2").err().unwrap();
+
+		assert_eq!(errors[0].to_string(), "[line 1] Error at end: Expect ')' after expression.");
+	}
+
+	#[test]
+	fn compile_should_report_too_deeply_nested_expression() {
+		let source = format!("{}1{}", "(".repeat(200_000), ")".repeat(200_000));
+
+		let errors = compile(&source).err().unwrap();
+
+		assert_eq!(errors, [ CompileError {
+			line: 1,
+			location: " at '('".to_string(),
+			message: "Expression nests too deeply.".to_string(),
+		} ]);
+	}
+
+	#[test]
+	fn compile_should_accept_nesting_up_to_the_limit() {
+		let source = format!("{}1{}", "(".repeat(MAX_NESTING_DEPTH - 1), ")".repeat(MAX_NESTING_DEPTH - 1));
+
+		assert!(compile(&source).is_ok());
+	}
+
+	#[test]
+	fn compile_should_report_scanner_errors_without_location() {
+		let errors = compile("1 + $").err().unwrap();
+
+		assert_eq!(errors[0].to_string(), "[line 1] Error: Unexpected character.");
+	}
+
+	#[test]
+	fn compile_should_fold_constant_expressions() {
+		let chunk = compile("60 * 60 * 24").unwrap();
+
+		assert_eq!(chunk.code, [ Op::Constant as u8, 0, Op::Return as u8 ]);
+		assert_eq!(chunk.constant_count(), 1);
+		assert_eq!(chunk.get_constant(0).as_number(), Some(86400.0));
+	}
+
+	#[test]
+	fn compile_should_fold_negated_grouping() {
+		let chunk = compile("-(1 + 2)").unwrap();
+
+		assert_eq!(chunk.code, [ Op::Constant as u8, 0, Op::Return as u8 ]);
+		assert_eq!(chunk.get_constant(0).as_number(), Some(-3.0));
+	}
+
+	#[test]
+	fn compile_should_fold_division_by_zero_like_ieee_754() {
+		let infinity = compile("1 / 0").unwrap();
+		let nan = compile("0 / 0").unwrap();
+
+		assert_eq!(infinity.get_constant(0).as_number(), Some(f64::INFINITY));
+		assert!(nan.get_constant(0).as_number().is_some_and(f64::is_nan));
+	}
+
+	#[test]
+	fn compile_should_fold_constant_operands_of_large_chunks() {
+		let source = (0..300).map(|i| i.to_string()).collect::<Vec<_>>().join(" + ");
+
+		let chunk = compile(&source).unwrap();
+
+		assert_eq!(chunk.code, [ Op::Constant as u8, 0, Op::Return as u8 ]);
+		assert_eq!(chunk.get_constant(0).as_number(), Some((0..300).sum::<i32>() as f64));
+	}
+
+	#[test]
+	fn compile_should_keep_folded_constant_on_line_of_first_operand() {
+		let chunk = compile("1 +\n2").unwrap();
+
+		assert_eq!(chunk.find_line(0), Some(1));
+	}
+
+	#[test]
+	fn compile_should_track_columns() {
+		let chunk = compile_unfolded("12 * -3");
+
+		let columns: Vec<_> = [ 0, 2, 4, 5 ].iter().map(|offset| chunk.find_span(*offset).unwrap().columns).collect();
+
+		assert_eq!(columns, [ Columns::new(1, 3), Columns::new(7, 8), Columns::new(6, 7), Columns::new(4, 5) ]);
+	}
+
+	#[test]
+	fn compile_should_cover_folded_operands_with_columns() {
+		let chunk = compile("(1 + 2 * 3) +\n4").unwrap();
+
+		assert_eq!(chunk.find_span(0).unwrap().columns, Columns::new(2, 14));
+	}
+
 
-pub fn compile(source: &str) {
-	let _scanner = Scanner::new(source);
 }
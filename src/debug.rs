@@ -1,56 +1,373 @@
+//! Disassembler turning chunks into listings formatted as text or JSON
+
+use std::fmt;
+use std::fmt::Write;
+
 use crate::chunk::Chunk;
-use crate::op::*;
+use crate::chunk::Columns;
+use crate::op::Op;
+use crate::op::Operand;
+use crate::op::decode_instruction;
 use crate::value::Value;
+use crate::verifier::VerifyError;
 
 /// Returns a string representation of the given opcode
 pub fn op_to_string(op: u8) -> &'static str {
-	match op {
-		OP_CONSTANT => "OP_CONSTANT",
-		OP_ADD => "OP_ADD",
-		OP_SUBTRACT => "OP_SUBTRACT",
-		OP_MULTIPLY => "OP_MULTIPLY",
-		OP_DIVIDE => "OP_DIVIDE",
-		OP_NEGATE => "OP_NEGATE",
-		OP_RETURN => "OP_RETURN",
-		OP_CONSTANT_LONG => "OP_CONSTANT_LONG",
-		_ => "OP_UNKNOWN"
-	}
-}
-
-/// Prints a debug representation of a value
-pub fn print_value(value: &Value) {
-	print!("{}", value);
-}
-
-/// Disassembles the instruction at the given code offset in the chunk and returns the next code offset
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-	print!("{offset:04} ");
-	let instruction_line = chunk.find_line(offset).unwrap();
-	if offset > 0 && instruction_line == chunk.find_line(offset - 1).unwrap() {
-		print!("   | ");
-	} else {
-		print!("{instruction_line:04} ");
-	}
-
-	let opcode = chunk.code[offset];
-	print!("{:<16} ", op_to_string(opcode));
-	match opcode {
-		OP_CONSTANT => constant_instruction("OP_CONSTANT", chunk, offset),
-		OP_CONSTANT_LONG => constant_instruction("OP_CONSTANT_LONG", chunk, offset),
-		_ => {}
-	};
-	println!();
-	offset + op_size(opcode)
-}
-
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) {
-	let const_id_bytes: [u8;4] = if name == "OP_CONSTANT" {
-		[ 0, 0, 0, chunk.code[offset + 1] ]
-	} else {
-		[ 0, chunk.code[offset + 1], chunk.code[offset + 2], chunk.code[offset + 3] ]
-	};
-	let const_value = chunk.get_constant(u32::from_be_bytes(const_id_bytes) as usize);
-	print!("'");
-	print_value(const_value);
-	print!("'");
+	Op::try_from(op).map_or("OP_UNKNOWN", Op::name)
+}
+
+/// A decoded instruction
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct Instruction {
+
+	pub offset: usize,
+
+	/// Number of code bytes taken by the instruction, including its operands
+	pub size: usize,
+
+	/// Source line, `None` if the chunk's line table doesn't cover the instruction
+	pub line: Option<u32>,
+
+	/// Whether the instruction is on the same line as the code byte preceding it
+	pub continues_line: bool,
+
+	/// Columns of the source line the instruction was compiled from, unknown if the chunk has no column information
+	pub columns: Columns,
+
+	pub opcode: u8,
+
+	/// Opcode of the instruction prefixed by [Op::Wide]
+	pub widened_opcode: Option<u8>,
+
+	pub operand: Option<usize>,
+
+	/// Value of the constant the operand refers to
+	pub constant: Option<Value>,
+
+	/// Why the instruction is malformed, if it is
+	pub error: Option<VerifyError>,
+
+}
+
+impl Instruction {
+
+	/// Decodes the instruction at the given code offset, returns `None` if the offset is past the end of the code
+	pub fn decode(chunk: &Chunk, offset: usize) -> Option<Self> {
+		let code = &chunk.code;
+		let opcode = *code.get(offset)?;
+		let line = chunk.find_line(offset);
+		let mut instruction = Self {
+			offset,
+			size: 1,
+			line,
+			continues_line: offset > 0 && line.is_some() && line == chunk.find_line(offset - 1),
+			columns: chunk.find_columns(offset),
+			opcode,
+			widened_opcode: None,
+			operand: None,
+			constant: None,
+			error: None,
+		};
+
+		match decode_instruction(chunk, offset) {
+			Ok(decoded) => {
+				instruction.size = decoded.size;
+				instruction.widened_opcode = decoded.widened.then_some(decoded.op.into());
+				instruction.operand = decoded.operand;
+				if decoded.op.operand() == Operand::Constant {
+					instruction.constant = decoded.operand.and_then(|index| chunk.find_constant(index)).copied();
+				}
+			},
+			Err(error) => {
+				// the malformed instruction spans the bytes its opcode claims, as far as the code goes
+				instruction.size = Op::try_from(opcode).map_or(1, Op::size).min(code.len() - offset);
+				if opcode == u8::from(Op::Wide) && instruction.size > 1 {
+					instruction.widened_opcode = Some(code[offset + 1]);
+				}
+				if let VerifyError::MissingConstant { index, .. } = error {
+					instruction.operand = Some(index);
+				}
+				instruction.error = Some(error);
+			},
+		}
+		Some(instruction)
+	}
+
+	/// Returns the mnemonic of the instruction
+	pub fn name(&self) -> &'static str {
+		op_to_string(self.opcode)
+	}
+
+	fn write_json(&self, out: &mut String) -> fmt::Result {
+		write!(out, "{{\"offset\":{},\"size\":{},\"line\":", self.offset, self.size)?;
+		match self.line {
+			Some(line) => write!(out, "{line}")?,
+			None => out.push_str("null"),
+		}
+		if self.columns.is_known() {
+			write!(out, ",\"columns\":[{},{}]", self.columns.start, self.columns.end)?;
+		}
+		write!(out, ",\"opcode\":{},\"name\":", self.opcode)?;
+		write_json_string(out, self.name())?;
+		if let Some(widened_opcode) = self.widened_opcode {
+			write!(out, ",\"widened_opcode\":{widened_opcode},\"widened_name\":")?;
+			write_json_string(out, op_to_string(widened_opcode))?;
+		}
+		if let Some(operand) = self.operand {
+			write!(out, ",\"operand\":{operand}")?;
+		}
+		if let Some(constant) = self.constant {
+			out.push_str(",\"constant\":");
+			match (constant.as_number(), constant.as_bool()) {
+				// JSON has no representation for infinities and NaN
+				(Some(number), _) if !number.is_finite() => write_json_string(out, &constant.to_string())?,
+				(Some(number), _) => write!(out, "{number}")?,
+				(_, Some(value)) => write!(out, "{value}")?,
+				_ => out.push_str("null"),
+			}
+		}
+		if let Some(error) = &self.error {
+			out.push_str(",\"error\":");
+			write_json_string(out, &error.to_string())?;
+		}
+		out.push('}');
+		Ok(())
+	}
+
+}
+
+/// Formats the instruction as a single line like `0002    | OP_CONSTANT      '1.5'`, or with its columns like
+/// `0002 0001:5-7    OP_CONSTANT      '1.5'` when they are known
+impl fmt::Display for Instruction {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let location = Location { line: self.line, continues_line: self.continues_line, columns: self.columns };
+		write!(f, "{:04} {location} ", self.offset)?;
+		write!(f, "{:<16} ", self.name())?;
+		if let Some(widened_opcode) = self.widened_opcode {
+			write!(f, "{:<16} ", op_to_string(widened_opcode))?;
+		}
+		match (self.constant, self.operand) {
+			(Some(constant), _) => write!(f, "'{constant}'")?,
+			(None, Some(operand)) => write!(f, "#{operand}")?,
+			(None, None) => {},
+		}
+		if let Some(error) = &self.error {
+			write!(f, " ; {error}")?;
+		}
+		Ok(())
+	}
+
+}
+
+/// Source location column of a listing, the line or `|` if it continues the previous one, or `line:start-end` when
+/// the columns are known
+pub struct Location {
+
+	pub line: Option<u32>,
+
+	pub continues_line: bool,
+
+	pub columns: Columns,
+
+}
+
+impl fmt::Display for Location {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match (self.continues_line, self.line) {
+			(_, Some(line)) if self.columns.is_known() => write!(f, "{:<12}", format!("{line:04}:{}", self.columns)),
+			(true, _) => write!(f, "   |"),
+			(false, Some(line)) => write!(f, "{line:04}"),
+			(false, None) => write!(f, "????"),
+		}
+	}
+
+}
+
+/// Every instruction of a chunk, in code order
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct Listing {
+
+	pub instructions: Vec<Instruction>,
+
+}
+
+impl Listing {
+
+	pub fn new(chunk: &Chunk) -> Self {
+		let mut instructions = Vec::new();
+		let mut offset = 0;
+		while let Some(instruction) = Instruction::decode(chunk, offset) {
+			offset += instruction.size;
+			instructions.push(instruction);
+		}
+		Self { instructions }
+	}
+
+	pub fn instruction_count(&self) -> usize {
+		self.instructions.len()
+	}
+
+	/// Returns the number of code bytes taken by the instructions
+	pub fn code_size(&self) -> usize {
+		self.instructions.iter().map(|instruction| instruction.size).sum()
+	}
+
+	/// Returns the listing as a JSON object with an `instructions` array
+	pub fn to_json(&self) -> String {
+		let mut out = String::from("{\"instructions\":[");
+		for (index, instruction) in self.instructions.iter().enumerate() {
+			if index > 0 {
+				out.push(',');
+			}
+			// writing to a String can't fail
+			let _ = instruction.write_json(&mut out);
+		}
+		let _ = write!(out, "],\"instruction_count\":{},\"code_size\":{}}}", self.instruction_count(), self.code_size());
+		out
+	}
+
+}
+
+/// Formats the listing as text, one instruction per line
+impl fmt::Display for Listing {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for instruction in &self.instructions {
+			writeln!(f, "{instruction}")?;
+		}
+		Ok(())
+	}
+
+}
+
+fn write_json_string(out: &mut String, text: &str) -> fmt::Result {
+	out.push('"');
+	for c in text.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn listing_should_format_instructions_as_text() {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(1.5), 1).unwrap();
+		chunk.write(Op::Negate, 1);
+		chunk.write_wide(Op::Constant, 0, 2).unwrap();
+		chunk.write(Op::Return, 2);
+
+		let sut = Listing::new(&chunk);
+
+		assert_eq!(sut.to_string(), "\
+			0000 0001 OP_CONSTANT      '1.5'\n\
+			0002    | OP_NEGATE        \n\
+			0003 0002 OP_WIDE          OP_CONSTANT      '1.5'\n\
+			0008    | OP_RETURN        \n");
+	}
+
+	#[test]
+	fn listing_should_show_known_columns_next_to_the_line() {
+		let mut chunk = Chunk::new();
+		chunk.set_columns(Columns::new(1, 3));
+		chunk.write_constant(Value::new(1.5), 1).unwrap();
+		chunk.set_columns(Columns::new(5, 5));
+		chunk.write(Op::Negate, 1);
+		chunk.set_columns(Columns::default());
+		chunk.write(Op::Return, 1);
+
+		let sut = Listing::new(&chunk);
+
+		assert_eq!(sut.to_string(), "\
+			0000 0001:1-3     OP_CONSTANT      '1.5'\n\
+			0002 0001:5-5     OP_NEGATE        \n\
+			0003    | OP_RETURN        \n");
+	}
+
+	#[test]
+	fn listing_should_continue_after_unknown_opcode() {
+		let mut chunk = Chunk::new();
+		chunk.write(0xffu8, 1);
+		chunk.write(Op::Return, 1);
+
+		let sut = Listing::new(&chunk);
+
+		assert_eq!(sut.instructions.len(), 2);
+		assert_eq!(sut.instructions[0].error, Some(VerifyError::UnknownOpcode { offset: 0, opcode: 0xff }));
+		assert_eq!(sut.instructions[1].name(), "OP_RETURN");
+	}
+
+	#[test]
+	fn listing_should_report_truncated_instruction() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Wide, 1);
+		chunk.write(Op::Constant, 1);
+
+		let sut = Listing::new(&chunk);
+
+		assert_eq!(sut.instructions.len(), 1);
+		assert_eq!(sut.instructions[0].size, 2);
+		assert_eq!(sut.instructions[0].error, Some(VerifyError::TruncatedInstruction { offset: 0 }));
+	}
+
+	#[test]
+	fn listing_should_show_index_of_missing_constant() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Constant, 1);
+		chunk.write(3u8, 1);
+
+		let sut = Listing::new(&chunk);
+
+		assert_eq!(sut.to_string(), "0000 0001 OP_CONSTANT      #3 ; Undefined constant 3 at offset 0.\n");
+	}
+
+	#[test]
+	fn listing_should_tolerate_missing_lines() {
+		let mut chunk = Chunk::new();
+		chunk.code.push(Op::Return as u8);
+
+		let sut = Listing::new(&chunk);
+
+		assert_eq!(sut.instructions[0].line, None);
+		assert_eq!(sut.to_string(), "0000 ???? OP_RETURN        \n");
+	}
+
+	#[test]
+	fn to_json_should_include_known_columns() {
+		let mut chunk = Chunk::new();
+		chunk.set_columns(Columns::new(3, 5));
+		chunk.write(Op::Return, 1);
+
+		let sut = Listing::new(&chunk).to_json();
+
+		assert!(sut.contains(r#""line":1,"columns":[3,5],"opcode":6"#), "{sut}");
+	}
+
+	#[test]
+	fn to_json_should_include_operands_and_constants() {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(f64::INFINITY), 1).unwrap();
+		chunk.write(Op::Return, 2);
+
+		let sut = Listing::new(&chunk).to_json();
+
+		assert_eq!(sut, concat!(
+			r#"{"instructions":["#,
+			r#"{"offset":0,"size":2,"line":1,"opcode":0,"name":"OP_CONSTANT","operand":0,"constant":"inf"},"#,
+			r#"{"offset":2,"size":1,"line":2,"opcode":6,"name":"OP_RETURN"}"#,
+			r#"],"instruction_count":2,"code_size":3}"#,
+		));
+	}
+
 }
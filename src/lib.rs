@@ -0,0 +1,33 @@
+pub mod asm;
+pub mod cache;
+pub mod chunk;
+pub mod compiler;
+pub mod debug;
+pub mod loxc;
+pub mod rle;
+pub mod op;
+pub mod output;
+pub mod peephole;
+pub mod register;
+pub mod scanner;
+pub mod value;
+pub mod verifier;
+pub mod vm;
+#[cfg(feature = "capi")]
+pub mod capi;
+
+use crate::vm::Backend;
+use crate::vm::InterpretError;
+
+/// Compiles and runs the given source code on the VM, compile errors are reported to the VM error output and returned
+pub fn interpret<B: Backend + ?Sized>(vm: &mut B, source: &str) -> Result<(), InterpretError> {
+	match compiler::compile(source) {
+		Ok(chunk) => vm.interpret(&chunk),
+		Err(compile_errors) => {
+			for compile_error in &compile_errors {
+				vm.report_error(compile_error);
+			}
+			Err(InterpretError::Compile(compile_errors))
+		},
+	}
+}
@@ -0,0 +1,329 @@
+//! Binary `.loxc` format for compiled chunks
+
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use crate::chunk::Chunk;
+use crate::chunk::ChunkError;
+use crate::chunk::Columns;
+use crate::value::Value;
+
+pub const MAGIC: &[u8;4] = b"LOXC";
+
+/// Version of the format, bumped on every incompatible change to the layout or to the instruction set
+pub const FORMAT_VERSION: u16 = 5;
+
+/// Extension of files holding compiled chunks
+pub const EXTENSION: &str = "loxc";
+
+const TAG_NUMBER: u8 = 0x00;
+
+const TAG_NIL: u8 = 0x01;
+
+const TAG_FALSE: u8 = 0x02;
+
+const TAG_TRUE: u8 = 0x03;
+
+/// Reasons a `.loxc` file can't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+
+	Io(io::Error),
+
+	/// Occurs when the file ends in the middle of the chunk
+	Truncated,
+
+	/// Occurs when the file doesn't start with [MAGIC]
+	NotLoxc,
+
+	/// Occurs when the file was written with another [FORMAT_VERSION]
+	UnsupportedVersion(u16),
+
+	UnknownConstantTag(u8),
+
+	/// Occurs when the line table doesn't cover exactly the code bytes
+	LineTableMismatch,
+
+	/// Occurs when the column table doesn't cover exactly the code bytes
+	ColumnTableMismatch,
+
+	/// Occurs when the file contains function chunks, which this interpreter doesn't support
+	UnsupportedFunctions,
+
+	/// Occurs when the file exceeds the limits of a chunk
+	Chunk(ChunkError),
+
+}
+
+impl fmt::Display for LoadError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Io(io_error) => write!(f, "{io_error}"),
+			Self::Truncated => write!(f, "Unexpected end of file."),
+			Self::NotLoxc => write!(f, "Not a compiled lox file."),
+			Self::UnsupportedVersion(version) => {
+				write!(f, "Unsupported format version {version}, expected {FORMAT_VERSION}.")
+			},
+			Self::UnknownConstantTag(tag) => write!(f, "Unknown constant type 0x{tag:02x}."),
+			Self::LineTableMismatch => write!(f, "Line table doesn't match the code."),
+			Self::ColumnTableMismatch => write!(f, "Column table doesn't match the code."),
+			Self::UnsupportedFunctions => write!(f, "Function chunks are not supported."),
+			Self::Chunk(chunk_error) => write!(f, "{chunk_error}"),
+		}
+	}
+
+}
+
+impl From<io::Error> for LoadError {
+
+	fn from(io_error: io::Error) -> Self {
+		match io_error.kind() {
+			io::ErrorKind::UnexpectedEof => Self::Truncated,
+			_ => Self::Io(io_error),
+		}
+	}
+
+}
+
+/// Writes the chunk as a complete `.loxc` file: magic, version, file id, code, constants, column runs, line runs and
+/// functions
+pub fn write(out: &mut dyn Write, chunk: &Chunk) -> io::Result<()> {
+	out.write_all(MAGIC)?;
+	out.write_all(&FORMAT_VERSION.to_be_bytes())?;
+	write_chunk(out, chunk)
+}
+
+/// Reads a complete `.loxc` file, the returned chunk still has to be verified before it is run
+pub fn read(input: &mut dyn Read) -> Result<Chunk, LoadError> {
+	let mut magic = [0u8;4];
+	input.read_exact(&mut magic)?;
+	if &magic != MAGIC {
+		return Err(LoadError::NotLoxc);
+	}
+	let version = u16::from_be_bytes(read_array(input)?);
+	if version != FORMAT_VERSION {
+		return Err(LoadError::UnsupportedVersion(version));
+	}
+	read_chunk(input)
+}
+
+fn write_chunk(out: &mut dyn Write, chunk: &Chunk) -> io::Result<()> {
+	out.write_all(&chunk.file().to_be_bytes())?;
+
+	write_len(out, chunk.code.len())?;
+	out.write_all(&chunk.code)?;
+
+	write_len(out, chunk.constant_count())?;
+	for const_id in 0..chunk.constant_count() {
+		let constant = chunk.get_constant(const_id);
+		match (constant.as_number(), constant.as_bool()) {
+			(Some(number), _) => {
+				out.write_all(&[ TAG_NUMBER ])?;
+				out.write_all(&number.to_bits().to_be_bytes())?;
+			},
+			(_, Some(false)) => out.write_all(&[ TAG_FALSE ])?,
+			(_, Some(true)) => out.write_all(&[ TAG_TRUE ])?,
+			_ => out.write_all(&[ TAG_NIL ])?,
+		}
+	}
+
+	let column_runs: Vec<(Columns, u32)> = chunk.column_runs().collect();
+	write_len(out, column_runs.len())?;
+	for (columns, length) in column_runs {
+		out.write_all(&columns.start.to_be_bytes())?;
+		out.write_all(&columns.end.to_be_bytes())?;
+		out.write_all(&length.to_be_bytes())?;
+	}
+
+	let runs: Vec<(u32, u32)> = chunk.line_runs().collect();
+	write_len(out, runs.len())?;
+	for (line, length) in runs {
+		out.write_all(&line.to_be_bytes())?;
+		out.write_all(&length.to_be_bytes())?;
+	}
+
+	write_len(out, 0)
+}
+
+fn read_chunk(input: &mut dyn Read) -> Result<Chunk, LoadError> {
+	let mut chunk = Chunk::new();
+	chunk.set_file(u32::from_be_bytes(read_array(input)?));
+
+	let code_len = read_len(input)?;
+	let mut code = Vec::new();
+	input.take(code_len as u64).read_to_end(&mut code)?;
+	if code.len() != code_len {
+		return Err(LoadError::Truncated);
+	}
+
+	for _ in 0..read_len(input)? {
+		let [ tag ] = read_array(input)?;
+		let value = match tag {
+			TAG_NUMBER => Value::new(f64::from_bits(u64::from_be_bytes(read_array(input)?))),
+			TAG_NIL => Value::nil(),
+			TAG_FALSE => Value::boolean(false),
+			TAG_TRUE => Value::boolean(true),
+			_ => return Err(LoadError::UnknownConstantTag(tag)),
+		};
+		chunk.add_constant(value).map_err(LoadError::Chunk)?;
+	}
+
+	let mut column_runs = Vec::new();
+	let mut column_table_len = 0;
+	for _ in 0..read_len(input)? {
+		let start = u16::from_be_bytes(read_array(input)?);
+		let end = u16::from_be_bytes(read_array(input)?);
+		let length = u32::from_be_bytes(read_array(input)?);
+		column_table_len += length as usize;
+		column_runs.push((Columns { start, end }, length));
+	}
+	if column_table_len != code.len() {
+		return Err(LoadError::ColumnTableMismatch);
+	}
+	let mut code_columns = column_runs.into_iter()
+		.flat_map(|(columns, length)| std::iter::repeat_n(columns, length as usize));
+
+	let mut code_bytes = code.into_iter();
+	for _ in 0..read_len(input)? {
+		let line = u32::from_be_bytes(read_array(input)?);
+		let length = u32::from_be_bytes(read_array(input)?);
+		for _ in 0..length {
+			let byte = code_bytes.next().ok_or(LoadError::LineTableMismatch)?;
+			chunk.set_columns(code_columns.next().unwrap_or_default());
+			chunk.write(byte, line);
+		}
+	}
+	if code_bytes.next().is_some() {
+		return Err(LoadError::LineTableMismatch);
+	}
+
+	if read_len(input)? != 0 {
+		return Err(LoadError::UnsupportedFunctions);
+	}
+	Ok(chunk)
+}
+
+fn write_len(out: &mut dyn Write, len: usize) -> io::Result<()> {
+	let len = u32::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+	out.write_all(&len.to_be_bytes())
+}
+
+fn read_len(input: &mut dyn Read) -> io::Result<usize> {
+	Ok(u32::from_be_bytes(read_array(input)?) as usize)
+}
+
+fn read_array<const N: usize>(input: &mut dyn Read) -> io::Result<[u8;N]> {
+	let mut bytes = [0u8;N];
+	input.read_exact(&mut bytes)?;
+	Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::op::Op;
+
+	fn write_to_vec(chunk: &Chunk) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		write(&mut bytes, chunk).unwrap();
+		bytes
+	}
+
+	#[test]
+	fn read_should_return_written_chunk() {
+		let mut chunk = Chunk::new();
+		chunk.set_file(7);
+		chunk.set_columns(Columns::new(1, 4));
+		chunk.write_constant(Value::new(1.5), 1).unwrap();
+		chunk.set_columns(Columns::new(1, 5));
+		chunk.write_constant(Value::new(-2.0), 2).unwrap();
+		chunk.set_columns(Columns::new(6, 7));
+		chunk.write(Op::Add, 2);
+		chunk.write(Op::Return, 3);
+
+		let result = read(&mut &write_to_vec(&chunk)[..]).unwrap();
+
+		assert_eq!(result.file(), 7);
+		assert_eq!(result.code, chunk.code);
+		assert_eq!(result.line_runs().collect::<Vec<_>>(), chunk.line_runs().collect::<Vec<_>>());
+		assert_eq!(result.column_runs().collect::<Vec<_>>(), chunk.column_runs().collect::<Vec<_>>());
+		assert_eq!(result.constant_count(), 2);
+		assert_eq!(result.get_constant(0).as_number(), Some(1.5));
+		assert_eq!(result.get_constant(1).as_number(), Some(-2.0));
+	}
+
+	#[test]
+	fn read_should_return_constants_of_every_kind() {
+		let mut chunk = Chunk::new();
+		for value in [ Value::nil(), Value::boolean(false), Value::boolean(true), Value::new(f64::NAN) ] {
+			chunk.add_constant(value).unwrap();
+		}
+
+		let result = read(&mut &write_to_vec(&chunk)[..]).unwrap();
+
+		assert_eq!(result.get_constant(0), &Value::nil());
+		assert_eq!(result.get_constant(1), &Value::boolean(false));
+		assert_eq!(result.get_constant(2), &Value::boolean(true));
+		assert!(result.get_constant(3).as_number().is_some_and(f64::is_nan));
+	}
+
+	#[test]
+	fn read_should_reject_other_files() {
+		let result = read(&mut &b"#!/usr/bin/env lox"[..]);
+
+		assert!(matches!(result, Err(LoadError::NotLoxc)));
+	}
+
+	#[test]
+	fn read_should_reject_other_format_versions() {
+		let mut bytes = write_to_vec(&Chunk::new());
+		bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+
+		let result = read(&mut &bytes[..]);
+
+		assert!(matches!(result, Err(LoadError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1));
+	}
+
+	#[test]
+	fn read_should_reject_truncated_files() {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(1.5), 1).unwrap();
+		let bytes = write_to_vec(&chunk);
+
+		let result = read(&mut &bytes[..bytes.len() - 1]);
+
+		assert!(matches!(result, Err(LoadError::Truncated)));
+	}
+
+	#[test]
+	fn read_should_reject_line_table_not_covering_code() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Return, 1);
+		let mut bytes = write_to_vec(&chunk);
+		// the last 4 bytes are the function count, preceded by the length of the only line run
+		let run_length_offset = bytes.len() - 8;
+		bytes[run_length_offset..run_length_offset + 4].copy_from_slice(&2u32.to_be_bytes());
+
+		let result = read(&mut &bytes[..]);
+
+		assert!(matches!(result, Err(LoadError::LineTableMismatch)));
+	}
+
+	#[test]
+	fn read_should_reject_column_table_not_covering_code() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Return, 1);
+		let mut bytes = write_to_vec(&chunk);
+		// the column table follows the file id, the code and the empty constant table, its only run ends with the run length
+		let run_length_offset = MAGIC.len() + 2 + 4 + 4 + chunk.code.len() + 4 + 4 + 4;
+		bytes[run_length_offset..run_length_offset + 4].copy_from_slice(&2u32.to_be_bytes());
+
+		let result = read(&mut &bytes[..]);
+
+		assert!(matches!(result, Err(LoadError::ColumnTableMismatch)));
+	}
+
+}
@@ -1,62 +1,206 @@
-mod chunk;
-mod compiler;
-#[cfg(feature = "trace")]
-mod debug;
-mod rle;
-mod op;
-mod scanner;
-mod value;
-mod vm;
-
 use std::io::Write;
+use std::path::Path;
 
 use sysexits::ExitCode;
 
-use crate::vm::InterpretError;
-use crate::vm::VM;
+use lox::cache;
+use lox::chunk::Chunk;
+use lox::compiler;
+use lox::compiler::CompilerOptions;
+use lox::debug::Listing;
+use lox::interpret;
+use lox::loxc;
+use lox::loxc::LoadError;
+use lox::peephole::Report;
+use lox::register;
+use lox::register::RegisterVM;
+use lox::vm::Backend;
+use lox::vm::InterpretError;
+use lox::vm::TraceMode;
+use lox::vm::VM;
+
+const USAGE: &str = "Usage: lox [--no-cache] [--no-optimize] [--disassemble] [--trace | --trace-stack] [--register-vm] [path]";
+
+/// Machines the CLI can run chunks on
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)]
+enum Machine {
+
+	Stack,
+
+	/// The experimental register machine, running chunks translated from stack code
+	Register,
+
+}
+
+/// Options given on the command line
+struct Options {
+
+	use_cache: bool,
+
+	/// Whether the compiler optimizes, disabled to compare with unoptimized output
+	optimize: bool,
+
+	/// Prints the listing of the chunk instead of running it
+	disassemble: bool,
+
+	trace_mode: TraceMode,
+
+	machine: Machine,
+
+}
+
+impl Options {
+
+	/// Creates the machine selected by the options, traces go to stderr by default
+	fn new_backend(&self) -> Box<dyn Backend> {
+		let mut backend: Box<dyn Backend> = match self.machine {
+			Machine::Stack => Box::new(VM::<256>::new()),
+			Machine::Register => Box::new(RegisterVM::new()),
+		};
+		backend.set_trace_mode(self.trace_mode);
+		backend
+	}
+
+	fn compiler_options(&self) -> CompilerOptions {
+		CompilerOptions { fold_constants: self.optimize, peephole: self.optimize }
+	}
+
+}
 
 fn main() -> ExitCode {
-	let args: Vec<String> = std::env::args().collect();
-	if args.len() == 1 {
-		repl()
-	} else if args.len() == 2 {
-		run_file(&args[1][..])
-	} else {
-		println!("Usage: lox [path]");
-		ExitCode::Usage
+	let mut options = Options { use_cache: true, optimize: true, disassemble: false, trace_mode: TraceMode::Off, machine: Machine::Stack };
+	let mut paths = Vec::new();
+	for arg in std::env::args().skip(1) {
+		match &arg[..] {
+			"--no-cache" => options.use_cache = false,
+			"--no-optimize" => options.optimize = false,
+			"--disassemble" => options.disassemble = true,
+			// only one trace mode can be selected
+			"--trace" | "--trace-stack" if options.trace_mode != TraceMode::Off => {
+				println!("{USAGE}");
+				return ExitCode::Usage;
+			},
+			"--trace" => options.trace_mode = TraceMode::Ops,
+			"--trace-stack" => options.trace_mode = TraceMode::OpsAndStack,
+			"--register-vm" => options.machine = Machine::Register,
+			_ if arg.starts_with("--") => {
+				println!("{USAGE}");
+				return ExitCode::Usage;
+			},
+			_ => paths.push(arg),
+		}
+	}
+	// listings aren't run, so there would be nothing to trace
+	if options.disassemble && options.trace_mode != TraceMode::Off {
+		println!("{USAGE}");
+		return ExitCode::Usage;
+	}
+	match &paths[..] {
+		[] => repl(&options),
+		[ path ] => run_file(path, &options),
+		_ => {
+			println!("{USAGE}");
+			ExitCode::Usage
+		},
 	}
 }
 
-fn run_file(filename: &str) -> ExitCode {
-	let Ok(source) = std::fs::read_to_string(filename) else {
-		return ExitCode::IoErr;
+fn run_file(filename: &str, options: &Options) -> ExitCode {
+	let mut backend = options.new_backend();
+	let (chunk, report) = match load_chunk(filename, options, backend.as_mut()) {
+		Ok(loaded) => loaded,
+		Err(exit_code) => return exit_code,
 	};
-	let mut vm = VM::<256>::new();
-	match interpret(&mut vm, &source) {
+	if options.disassemble {
+		return match options.machine {
+			Machine::Stack => disassemble(&chunk, report),
+			Machine::Register => disassemble_registers(&chunk, report),
+		};
+	}
+	match backend.interpret(&chunk) {
 		Ok(_) => ExitCode::Ok,
 		Err(interpret_error) => interpret_error.to_exit_code(),
 	}
 }
 
-fn repl() -> ExitCode {
-	let mut vm = VM::<256>::new();
+fn disassemble(chunk: &Chunk, report: Option<Report>) -> ExitCode {
+	let listing = Listing::new(chunk);
+	print!("{listing}");
+	println!("; {} instructions, {} bytes", listing.instruction_count(), listing.code_size());
+	println!("; line table: {}, column table: {}", chunk.line_table_usage(), chunk.column_table_usage());
+	print_report(report);
+	ExitCode::Ok
+}
+
+/// Translates the chunk for the register machine and prints its listing
+fn disassemble_registers(chunk: &Chunk, report: Option<Report>) -> ExitCode {
+	let register_chunk = match register::translate(chunk) {
+		Ok(register_chunk) => register_chunk,
+		Err(translate_error) => {
+			eprintln!("{translate_error}");
+			return InterpretError::BadChunk.to_exit_code();
+		},
+	};
+	print!("{register_chunk}");
+	println!("; {} instructions, {} bytes, {} registers", register_chunk.instruction_count(),
+		register_chunk.chunk().code.len(), register_chunk.register_count());
+	print_report(report);
+	ExitCode::Ok
+}
+
+/// Prints the size reduction achieved by the peephole optimizer below a listing, if the chunk was optimized
+fn print_report(report: Option<Report>) {
+	if let Some(report) = report {
+		println!("; peephole: {report}");
+	}
+}
+
+/// Compiles a source file, or loads a compiled chunk which the VM verifies before running it, the peephole
+/// report is only available when the source was compiled
+fn load_chunk(filename: &str, options: &Options, vm: &mut dyn Backend) -> Result<(Chunk, Option<Report>), ExitCode> {
+	let path = Path::new(filename);
+	if path.extension().is_some_and(|extension| extension == loxc::EXTENSION) {
+		let file = std::fs::File::open(filename).map_err(|_| ExitCode::IoErr)?;
+		return match loxc::read(&mut std::io::BufReader::new(file)) {
+			Ok(chunk) => Ok((chunk, None)),
+			Err(LoadError::Io(_)) => Err(ExitCode::IoErr),
+			Err(load_error) => {
+				eprintln!("{filename}: {load_error}");
+				Err(ExitCode::DataErr)
+			},
+		};
+	}
+	let source = std::fs::read_to_string(filename).map_err(|_| ExitCode::IoErr)?;
+	// the cache only holds chunks compiled with the default options, listings are compiled to show the peephole report
+	let compiled = match options.use_cache && options.optimize && !options.disassemble {
+		true => cache::compile(path, &source).map(|chunk| (chunk, None)),
+		false => compiler::compile_with_report(&source, options.compiler_options()),
+	};
+	compiled.map_err(|compile_errors| {
+		for compile_error in &compile_errors {
+			vm.report_error(compile_error);
+		}
+		InterpretError::Compile(compile_errors).to_exit_code()
+	})
+}
+
+fn repl(options: &Options) -> ExitCode {
+	let mut backend = options.new_backend();
 	let mut buffer = String::new();
 	loop {
 		print!("> ");
-		if let Err(_) = std::io::stdout().flush() {
+		if std::io::stdout().flush().is_err() {
 			return ExitCode::IoErr;
 		}
-		let Ok(_) = std::io::stdin().read_line(&mut buffer) else {
+		let Ok(bytes_read) = std::io::stdin().read_line(&mut buffer) else {
 			return ExitCode::IoErr;
 		};
-		if let Err(interpret_error) = interpret(&mut vm, &buffer) {
-			return interpret_error.to_exit_code();
+		if bytes_read == 0 { // end of input
+			println!();
+			return ExitCode::Ok;
 		}
+		// errors have already been reported by the VM, the REPL carries on with the next line
+		let _ = interpret(backend.as_mut(), &buffer);
 		buffer.clear();
 	}
-	ExitCode::Ok
-}
-
-fn interpret<const N: usize>(vm: &mut VM<N>, source: &str) -> Result<(), InterpretError> {
-	Ok(())
 }
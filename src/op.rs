@@ -1,17 +1,251 @@
-pub const OP_CONSTANT: u8 = 0x00;
-pub const OP_ADD: u8 = 0x01;
-pub const OP_SUBTRACT: u8 = 0x02;
-pub const OP_MULTIPLY: u8 = 0x03;
-pub const OP_DIVIDE: u8 = 0x04;
-pub const OP_NEGATE: u8 = 0x05;
-pub const OP_RETURN: u8 = 0x06;
-pub const OP_CONSTANT_LONG: u8 = 0x07;
-
-/// Returns the size of opcode + operands in bytes
-pub fn op_size(op: u8) -> usize {
-	match op {
-		OP_CONSTANT => 2,
-		OP_CONSTANT_LONG => 4,
-		_ => 1
+use crate::chunk::Chunk;
+use crate::verifier::VerifyError;
+
+/// Number of bytes an operand takes when its instruction is prefixed with [Op::Wide]
+pub const WIDE_OPERAND_SIZE: usize = 3;
+
+/// Largest operand that can be encoded, using the [Op::Wide] prefix
+pub const MAX_OPERAND: usize = (1 << (WIDE_OPERAND_SIZE * 8)) - 1;
+
+/// Layout of the operand bytes following an opcode
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)]
+pub enum Operand {
+
+	None,
+
+	/// One byte index into the constant table
+	Constant,
+
+	/// Prefix operand: the opcode of the widened instruction followed by its operand as three big endian bytes
+	Wide,
+
+}
+
+impl Operand {
+
+	/// Returns the number of bytes taken by the operand
+	pub const fn size(self) -> usize {
+		match self {
+			Self::None => 0,
+			Self::Constant => 1,
+			Self::Wide => 1 + WIDE_OPERAND_SIZE,
+		}
+	}
+
+}
+
+/// Declares every instruction exactly once, generating [Op] along with its decoding, names, operand layouts
+/// and stack effects (values popped, values pushed)
+macro_rules! instructions {
+	( $( $(#[$attr:meta])* $variant:ident = $byte:literal, $name:literal, $operand:ident, $pops:literal, $pushes:literal; )* ) => {
+
+		#[repr(u8)]
+		#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Eq)] #[derive(Debug)]
+		pub enum Op {
+			$( $(#[$attr])* $variant = $byte, )*
+		}
+
+		impl Op {
+
+			/// Every instruction in the order of the instruction table
+			pub const ALL: &'static [Op] = &[ $( Op::$variant, )* ];
+
+			/// Returns the mnemonic of the instruction as shown by the disassembler
+			pub const fn name(self) -> &'static str {
+				match self {
+					$( Op::$variant => $name, )*
+				}
+			}
+
+			/// Returns the layout of the operand bytes following the opcode
+			pub const fn operand(self) -> Operand {
+				match self {
+					$( Op::$variant => Operand::$operand, )*
+				}
+			}
+
+			/// Returns the number of values the instruction pops from and pushes onto the stack, a
+			/// prefix like [Op::Wide] has the stack effect of the instruction it prefixes
+			pub const fn stack_effect(self) -> (usize, usize) {
+				match self {
+					$( Op::$variant => ($pops, $pushes), )*
+				}
+			}
+
+		}
+
+		impl TryFrom<u8> for Op {
+			type Error = u8;
+
+			/// Decodes an opcode, returning the byte itself if it isn't a known instruction
+			fn try_from(byte: u8) -> Result<Self, Self::Error> {
+				match byte {
+					$( $byte => Ok(Op::$variant), )*
+					_ => Err(byte)
+				}
+			}
+		}
+
+	};
+}
+
+instructions! {
+	/// Pushes a constant from the constant table
+	Constant = 0x00, "OP_CONSTANT", Constant, 0, 1;
+	Add = 0x01, "OP_ADD", None, 2, 1;
+	Subtract = 0x02, "OP_SUBTRACT", None, 2, 1;
+	Multiply = 0x03, "OP_MULTIPLY", None, 2, 1;
+	Divide = 0x04, "OP_DIVIDE", None, 2, 1;
+	Negate = 0x05, "OP_NEGATE", None, 1, 1;
+	/// Pops and prints the value on top of the stack
+	Return = 0x06, "OP_RETURN", None, 1, 0;
+	/// Prefixes an instruction whose operand doesn't fit into a single byte
+	Wide = 0x07, "OP_WIDE", Wide, 0, 0;
+}
+
+impl Op {
+
+	/// Returns the size of opcode + operands in bytes
+	pub const fn size(self) -> usize {
+		1 + self.operand().size()
+	}
+
+	/// Looks up an instruction by the mnemonic returned by [Op::name]
+	pub fn from_name(name: &str) -> Option<Self> {
+		Self::ALL.iter().copied().find(|op| op.name() == name)
+	}
+
+	/// Converts a byte into an instruction without checking it
+	///
+	/// # Safety
+	/// `byte` must be the opcode of an instruction, as guaranteed for every opcode of a verified chunk
+	#[inline]
+	pub const unsafe fn from_u8_unchecked(byte: u8) -> Self {
+		// Safety: Op is repr(u8) and the caller guarantees byte is one of its discriminants
+		unsafe { std::mem::transmute::<u8, Op>(byte) }
+	}
+
+	/// Returns whether the instruction carries an operand that can be widened with [Op::Wide]
+	pub const fn is_widenable(self) -> bool {
+		!matches!(self.operand(), Operand::None | Operand::Wide)
+	}
+
+}
+
+impl From<Op> for u8 {
+
+	fn from(op: Op) -> Self {
+		op as u8
+	}
+
+}
+
+/// An instruction decoded by [decode_instruction]
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)]
+pub struct DecodedOp {
+
+	/// The instruction that is executed, for [Op::Wide] the instruction it prefixes
+	pub op: Op,
+
+	/// Whether the instruction is prefixed with [Op::Wide]
+	pub widened: bool,
+
+	pub operand: Option<usize>,
+
+	/// Number of code bytes taken by the instruction, including prefix and operands
+	pub size: usize,
+
+}
+
+/// Decodes the instruction at the given code offset, checking that its opcode is known, its operands lie within
+/// the code and a constant operand refers to an existing constant
+#[inline]
+pub fn decode_instruction(chunk: &Chunk, offset: usize) -> Result<DecodedOp, VerifyError> {
+	let code = &chunk.code;
+	let opcode = *code.get(offset).ok_or(VerifyError::TruncatedInstruction { offset })?;
+	let op = Op::try_from(opcode).map_err(|opcode| VerifyError::UnknownOpcode { offset, opcode })?;
+	if offset + op.size() > code.len() {
+		return Err(VerifyError::TruncatedInstruction { offset });
+	}
+	let decoded = match op.operand() {
+		Operand::None => DecodedOp { op, widened: false, operand: None, size: op.size() },
+		Operand::Constant => DecodedOp { op, widened: false, operand: Some(code[offset + 1] as usize), size: op.size() },
+		Operand::Wide => {
+			let widened_op = Op::try_from(code[offset + 1])
+				.map_err(|opcode| VerifyError::UnknownOpcode { offset: offset + 1, opcode })?;
+			if !widened_op.is_widenable() {
+				return Err(VerifyError::InvalidWide { offset });
+			}
+			// Safety: the instruction size was checked against the code length above
+			let operand = unsafe { read_wide_operand(code.as_ptr().add(offset + 2)) };
+			DecodedOp { op: widened_op, widened: true, operand: Some(operand), size: op.size() }
+		},
+	};
+	if let (Operand::Constant, Some(index)) = (decoded.op.operand(), decoded.operand) && index >= chunk.constant_count() {
+		return Err(VerifyError::MissingConstant { offset, index });
+	}
+	Ok(decoded)
+}
+
+/// Reads an operand stored as [WIDE_OPERAND_SIZE] big endian bytes
+///
+/// # Safety
+/// `ptr` must point to at least [WIDE_OPERAND_SIZE] readable bytes
+#[inline]
+pub unsafe fn read_wide_operand(ptr: *const u8) -> usize {
+	let mut operand = 0;
+	for i in 0..WIDE_OPERAND_SIZE {
+		operand = (operand << 8) | unsafe { *ptr.add(i) } as usize;
+	}
+	operand
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::value::Value;
+
+	#[test]
+	fn try_from_should_decode_every_instruction() {
+		for op in Op::ALL {
+			assert_eq!(Op::try_from(*op as u8), Ok(*op));
+		}
+	}
+
+	#[test]
+	fn try_from_should_reject_unknown_bytes() {
+		assert_eq!(Op::try_from(0xff), Err(0xff));
+	}
+
+	#[test]
+	fn from_name_should_find_every_instruction() {
+		for op in Op::ALL {
+			assert_eq!(Op::from_name(op.name()), Some(*op));
+		}
+		assert_eq!(Op::from_name("OP_UNKNOWN"), None);
 	}
+
+	#[test]
+	fn decode_instruction_should_combine_widened_operand() {
+		let mut chunk = Chunk::new();
+		for i in 0..=0x0102 {
+			chunk.add_constant(Value::new(i as f64)).unwrap();
+		}
+		chunk.write_wide(Op::Constant, 0x0102, 1).unwrap();
+
+		let result = decode_instruction(&chunk, 0);
+
+		assert_eq!(result, Ok(DecodedOp { op: Op::Constant, widened: true, operand: Some(0x0102), size: 5 }));
+	}
+
+	#[test]
+	fn decode_instruction_should_reject_missing_widened_constant() {
+		let mut chunk = Chunk::new();
+		chunk.write_wide(Op::Constant, 0x0102, 1).unwrap();
+
+		let result = decode_instruction(&chunk, 0);
+
+		assert_eq!(result, Err(VerifyError::MissingConstant { offset: 0, index: 0x0102 }));
+	}
+
 }
@@ -0,0 +1,156 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Buffered writer the VM uses for each of its output sinks
+pub type Sink = io::BufWriter<Box<dyn Write>>;
+
+/// Wraps the given writer into a buffered VM sink
+pub fn sink(writer: Box<dyn Write>) -> Sink {
+	io::BufWriter::new(writer)
+}
+
+/// The sinks of a VM, shared by both machines
+pub struct Sinks {
+
+	/// Receives the output of the running program
+	pub output: Sink,
+
+	/// Receives error reports
+	pub error_output: Sink,
+
+	/// Receives instruction traces, stderr by default as a separately buffered stdout would print program output
+	/// ahead of the traces of the instructions producing it
+	pub trace_output: Sink,
+
+}
+
+impl Sinks {
+
+	/// Creates sinks writing the output to stdout, errors and traces to stderr
+	pub fn new() -> Self {
+		Self {
+			output: sink(Box::new(io::stdout())),
+			error_output: sink(Box::new(io::stderr())),
+			trace_output: sink(Box::new(io::stderr())),
+		}
+	}
+
+	/// Replaces the sink receiving program output, flushing the previous one
+	pub fn set_output(&mut self, writer: Box<dyn Write>) {
+		let _ = self.output.flush();
+		self.output = sink(writer);
+	}
+
+	/// Replaces the sink receiving error reports, flushing the previous one
+	pub fn set_error_output(&mut self, writer: Box<dyn Write>) {
+		let _ = self.error_output.flush();
+		self.error_output = sink(writer);
+	}
+
+	/// Replaces the sink receiving instruction traces, flushing the previous one
+	pub fn set_trace_output(&mut self, writer: Box<dyn Write>) {
+		let _ = self.trace_output.flush();
+		self.trace_output = sink(writer);
+	}
+
+	/// Writes the error to the error output and flushes it
+	pub fn report_error(&mut self, error: &dyn fmt::Display) {
+		let _ = writeln!(self.error_output, "{error}");
+		let _ = self.error_output.flush();
+	}
+
+	/// Flushes all sinks, sink errors are ignored as they must not abort the running program
+	pub fn flush(&mut self) {
+		let _ = self.output.flush();
+		let _ = self.error_output.flush();
+		let _ = self.trace_output.flush();
+	}
+
+}
+
+impl Default for Sinks {
+
+	fn default() -> Self {
+		Self::new()
+	}
+
+}
+
+/// In-memory sink, clones share the same buffer so output can be read back after handing one to a VM
+#[derive(Clone)] #[derive(Default)]
+pub struct MemorySink {
+
+	buffer: Rc<RefCell<Vec<u8>>>,
+
+}
+
+impl MemorySink {
+
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Returns everything written so far, replacing invalid UTF-8
+	pub fn contents(&self) -> String {
+		String::from_utf8_lossy(&self.buffer.borrow()).into_owned()
+	}
+
+	pub fn clear(&self) {
+		self.buffer.borrow_mut().clear();
+	}
+
+}
+
+impl Write for MemorySink {
+
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.buffer.borrow_mut().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn contents_should_be_shared_between_clones() {
+		let sut = MemorySink::new();
+		let mut writer = sut.clone();
+
+		write!(writer, "hello").unwrap();
+
+		assert_eq!(sut.contents(), "hello");
+	}
+
+	#[test]
+	fn set_output_should_flush_previous_sink() {
+		let output = MemorySink::new();
+		let mut sut = Sinks::new();
+		sut.set_output(Box::new(output.clone()));
+		write!(sut.output, "hello").unwrap();
+
+		sut.set_output(Box::new(MemorySink::new()));
+
+		assert_eq!(output.contents(), "hello");
+	}
+
+	#[test]
+	fn clear_should_empty_buffer() {
+		let mut sut = MemorySink::new();
+		write!(sut, "hello").unwrap();
+
+		sut.clear();
+
+		assert_eq!(sut.contents(), "");
+	}
+
+}
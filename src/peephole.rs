@@ -0,0 +1,191 @@
+//! Peephole optimizer rewriting instruction patterns in finished chunks
+//!
+//! This is a placeholder: the requested rewrites of pops, jumps, `OP_NOT` and dead code after returns need
+//! instructions the language doesn't have yet. The double negation and widening rewrites only apply to assembled
+//! or hand-built chunks and to chunks compiled without constant folding. Folded chunks come out unchanged, as the
+//! compiler already evaluates the negations and picks the shortest encoding.
+
+use std::fmt;
+
+use crate::chunk::Chunk;
+use crate::debug::Instruction;
+use crate::debug::Listing;
+use crate::op::Op;
+
+/// Sizes of a chunk before and after optimizing it, as counted by the disassembler
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct Report {
+
+	pub instructions_before: usize,
+
+	pub instructions_after: usize,
+
+	pub bytes_before: usize,
+
+	pub bytes_after: usize,
+
+}
+
+impl fmt::Display for Report {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} -> {} instructions, {} -> {} bytes",
+			self.instructions_before, self.instructions_after, self.bytes_before, self.bytes_after)
+	}
+
+}
+
+/// Returns the optimized chunk along with a report of the size reduction, malformed chunks are returned unchanged
+pub fn optimize(chunk: &Chunk) -> (Chunk, Report) {
+	let listing = Listing::new(chunk);
+	let optimized = match listing.instructions.iter().all(|instruction| instruction.error.is_none() && instruction.line.is_some()) {
+		true => rewrite(chunk, &listing.instructions),
+		false => chunk.clone(),
+	};
+	let optimized_listing = Listing::new(&optimized);
+	let report = Report {
+		instructions_before: listing.instruction_count(),
+		instructions_after: optimized_listing.instruction_count(),
+		bytes_before: listing.code_size(),
+		bytes_after: optimized_listing.code_size(),
+	};
+	(optimized, report)
+}
+
+fn rewrite(chunk: &Chunk, instructions: &[Instruction]) -> Chunk {
+	let mut optimized = chunk.clone();
+	optimized.truncate(0);
+	let mut index = 0;
+	while index < instructions.len() {
+		let instruction = &instructions[index];
+		let line = instruction.line.unwrap_or_default();
+		let mut next_index = index + 1;
+		// negating a number twice gives back the same number, other values would turn into NaN
+		if instruction.constant.is_some_and(|constant| constant.as_number().is_some()) {
+			while is_negate(instructions.get(next_index)) && is_negate(instructions.get(next_index + 1)) {
+				next_index += 2;
+			}
+		}
+		optimized.set_columns(chunk.find_columns(instruction.offset));
+		match (instruction.widened_opcode.map(Op::try_from), instruction.operand) {
+			// write_with_operand picks the shortest encoding, the operand has already been checked to be valid
+			(Some(Ok(widened_op)), Some(operand)) => {
+				let _ = optimized.write_with_operand(widened_op, operand, line);
+			},
+			_ => {
+				for byte in &chunk.code[instruction.offset..instruction.offset + instruction.size] {
+					optimized.write(*byte, line);
+				}
+			},
+		}
+		index = next_index;
+	}
+	optimized
+}
+
+fn is_negate(instruction: Option<&Instruction>) -> bool {
+	instruction.is_some_and(|instruction| instruction.opcode == Op::Negate as u8)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm::assemble;
+	use crate::chunk::Columns;
+	use crate::compiler::CompilerOptions;
+	use crate::compiler::compile_with_options;
+	use crate::value::Value;
+
+	#[test]
+	fn optimize_should_remove_double_negation_of_number_constant() {
+		let chunk = assemble("1 OP_CONSTANT 1\n2 OP_NEGATE\n3 OP_NEGATE\n4 OP_RETURN").unwrap();
+
+		let (sut, report) = optimize(&chunk);
+
+		assert_eq!(sut.code, [ Op::Constant as u8, 0, Op::Return as u8 ]);
+		assert_eq!(sut.line_runs().collect::<Vec<_>>(), [ (1, 2), (4, 1) ]);
+		assert_eq!(report.to_string(), "4 -> 2 instructions, 5 -> 3 bytes");
+	}
+
+	#[test]
+	fn optimize_should_keep_odd_negation() {
+		let chunk = assemble("OP_CONSTANT 1\nOP_NEGATE\nOP_NEGATE\nOP_NEGATE\nOP_RETURN").unwrap();
+
+		let (sut, _) = optimize(&chunk);
+
+		assert_eq!(sut.code, [ Op::Constant as u8, 0, Op::Negate as u8, Op::Return as u8 ]);
+	}
+
+	#[test]
+	fn optimize_should_keep_double_negation_of_other_values() {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::nil(), 1).unwrap();
+		chunk.write(Op::Negate, 1);
+		chunk.write(Op::Negate, 1);
+		chunk.write(Op::Return, 1);
+
+		let (sut, _) = optimize(&chunk);
+
+		assert_eq!(sut.code, chunk.code);
+	}
+
+	#[test]
+	fn optimize_should_keep_double_negation_of_computed_value() {
+		let chunk = assemble("OP_CONSTANT 1\nOP_CONSTANT 2\nOP_ADD\nOP_NEGATE\nOP_NEGATE\nOP_RETURN").unwrap();
+
+		let (sut, _) = optimize(&chunk);
+
+		assert_eq!(sut.code, chunk.code);
+	}
+
+	#[test]
+	fn optimize_should_keep_columns_of_remaining_instructions() {
+		let mut chunk = Chunk::new();
+		chunk.set_columns(Columns::new(3, 4));
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.set_columns(Columns::new(2, 2));
+		chunk.write(Op::Negate, 1);
+		chunk.set_columns(Columns::new(1, 1));
+		chunk.write(Op::Negate, 1);
+		chunk.set_columns(Columns::new(5, 6));
+		chunk.write(Op::Return, 1);
+
+		let (sut, _) = optimize(&chunk);
+
+		assert_eq!(sut.column_runs().collect::<Vec<_>>(), [ (Columns::new(3, 4), 2), (Columns::new(5, 6), 1) ]);
+	}
+
+	#[test]
+	fn optimize_should_narrow_widened_instructions_with_small_operands() {
+		let chunk = assemble("OP_WIDE OP_CONSTANT 1\nOP_RETURN").unwrap();
+
+		let (sut, report) = optimize(&chunk);
+
+		assert_eq!(sut.code, [ Op::Constant as u8, 0, Op::Return as u8 ]);
+		assert_eq!(report.bytes_before - report.bytes_after, 3);
+	}
+
+	#[test]
+	fn optimize_should_leave_folded_compiler_output_unchanged() {
+		let chunk = compile_with_options("1 + --2 * (3 - 4)", CompilerOptions { fold_constants: true, peephole: false }).unwrap();
+
+		let (sut, report) = optimize(&chunk);
+
+		assert_eq!(sut.code, chunk.code);
+		assert_eq!(report.bytes_before, report.bytes_after);
+	}
+
+	#[test]
+	fn optimize_should_return_malformed_chunk_unchanged() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Negate, 1);
+		chunk.write(Op::Negate, 1);
+		chunk.write(0xffu8, 1);
+
+		let (sut, report) = optimize(&chunk);
+
+		assert_eq!(sut.code, chunk.code);
+		assert_eq!(report.instructions_before, report.instructions_after);
+	}
+
+}
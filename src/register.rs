@@ -0,0 +1,805 @@
+//! Experimental register machine running three-address code translated from stack chunks
+
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use std::ops::RangeInclusive;
+
+use crate::chunk::Chunk;
+use crate::chunk::Span;
+use crate::debug::Listing;
+use crate::debug::Location;
+use crate::op::Op;
+use crate::op::WIDE_OPERAND_SIZE;
+use crate::op::read_wide_operand;
+use crate::output::Sinks;
+use crate::value::Value;
+use crate::verifier;
+use crate::verifier::VerifyError;
+use crate::vm::Backend;
+use crate::vm::InterpretError;
+use crate::vm::RuntimeError;
+use crate::vm::TraceMode;
+use crate::vm::is_traced_line;
+
+/// Registers are addressed by a single byte
+const MAX_REGISTERS: usize = u8::MAX as usize + 1;
+
+/// Layout of the operand bytes following a register opcode
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)]
+pub enum RegOperands {
+
+	/// The given number of register bytes
+	Registers(usize),
+
+	/// The given number of register bytes followed by a three byte big endian constant index
+	RegistersAndConstant(usize),
+
+}
+
+impl RegOperands {
+
+	/// Returns the number of register operands
+	pub const fn register_count(self) -> usize {
+		match self {
+			Self::Registers(count) | Self::RegistersAndConstant(count) => count,
+		}
+	}
+
+	/// Returns the number of bytes taken by the operands
+	pub const fn size(self) -> usize {
+		match self {
+			Self::Registers(count) => count,
+			Self::RegistersAndConstant(count) => count + WIDE_OPERAND_SIZE,
+		}
+	}
+
+}
+
+/// Declares every register instruction exactly once, generating [RegOp] along with its decoding, names and
+/// operand layouts
+macro_rules! register_instructions {
+	( $( $(#[$attr:meta])* $variant:ident = $byte:literal, $name:literal, $operands:expr; )* ) => {
+
+		#[repr(u8)]
+		#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)]
+		pub enum RegOp {
+			$( $(#[$attr])* $variant = $byte, )*
+		}
+
+		impl RegOp {
+
+			/// Every instruction in the order of the instruction table
+			pub const ALL: &'static [RegOp] = &[ $( RegOp::$variant, )* ];
+
+			/// Returns the mnemonic of the instruction as shown in listings
+			pub const fn name(self) -> &'static str {
+				match self {
+					$( RegOp::$variant => $name, )*
+				}
+			}
+
+			/// Returns the layout of the operand bytes following the opcode
+			pub const fn operands(self) -> RegOperands {
+				match self {
+					$( RegOp::$variant => $operands, )*
+				}
+			}
+
+		}
+
+		impl TryFrom<u8> for RegOp {
+			type Error = u8;
+
+			/// Decodes an opcode, returning the byte itself if it isn't a known instruction
+			fn try_from(byte: u8) -> Result<Self, Self::Error> {
+				match byte {
+					$( $byte => Ok(RegOp::$variant), )*
+					_ => Err(byte)
+				}
+			}
+		}
+
+	};
+}
+
+register_instructions! {
+	/// `dst, constant`: loads a constant into a register
+	LoadConstant = 0x00, "OP_LOAD_CONSTANT", RegOperands::RegistersAndConstant(1);
+	/// `dst, left, right`
+	Add = 0x01, "OP_ADD", RegOperands::Registers(3);
+	Subtract = 0x02, "OP_SUBTRACT", RegOperands::Registers(3);
+	Multiply = 0x03, "OP_MULTIPLY", RegOperands::Registers(3);
+	Divide = 0x04, "OP_DIVIDE", RegOperands::Registers(3);
+	/// `dst, left, constant`: like [RegOp::Add] with a constant as right operand
+	AddConstant = 0x05, "OP_ADD_CONSTANT", RegOperands::RegistersAndConstant(2);
+	SubtractConstant = 0x06, "OP_SUBTRACT_CONSTANT", RegOperands::RegistersAndConstant(2);
+	MultiplyConstant = 0x07, "OP_MULTIPLY_CONSTANT", RegOperands::RegistersAndConstant(2);
+	DivideConstant = 0x08, "OP_DIVIDE_CONSTANT", RegOperands::RegistersAndConstant(2);
+	/// `dst, src`
+	Negate = 0x09, "OP_NEGATE", RegOperands::Registers(2);
+	/// `src`: prints the value of a register
+	Return = 0x0a, "OP_RETURN", RegOperands::Registers(1);
+}
+
+impl RegOp {
+
+	/// Returns the size of opcode + operands in bytes
+	pub const fn size(self) -> usize {
+		1 + self.operands().size()
+	}
+
+	/// Converts a byte into an instruction without checking it
+	///
+	/// # Safety
+	/// `byte` must be the opcode of an instruction
+	#[inline]
+	const unsafe fn from_u8_unchecked(byte: u8) -> Self {
+		// Safety: RegOp is repr(u8) and the caller guarantees byte is one of its discriminants
+		unsafe { std::mem::transmute::<u8, RegOp>(byte) }
+	}
+
+}
+
+impl From<RegOp> for u8 {
+
+	fn from(op: RegOp) -> Self {
+		op as u8
+	}
+
+}
+
+/// Reasons a stack chunk can't be translated into a register chunk
+#[derive(PartialEq)] #[derive(Debug)]
+pub enum TranslateError {
+
+	/// The stack chunk is malformed
+	Verify(VerifyError),
+
+	/// Occurs when the stack grows deeper than there are registers
+	TooManyRegisters { offset: usize },
+
+}
+
+impl fmt::Display for TranslateError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Verify(verify_error) => write!(f, "{verify_error}"),
+			Self::TooManyRegisters { offset } => write!(f, "Too many registers needed at offset {offset}."),
+		}
+	}
+
+}
+
+/// Code of the register machine along with the number of registers it uses, only built by [translate] so its
+/// code is always well-formed
+#[derive(Clone)]
+pub struct RegisterChunk {
+
+	chunk: Chunk,
+
+	register_count: usize,
+
+}
+
+impl RegisterChunk {
+
+	/// Returns the chunk holding the register code, constants and source map
+	pub fn chunk(&self) -> &Chunk {
+		&self.chunk
+	}
+
+	pub fn register_count(&self) -> usize {
+		self.register_count
+	}
+
+	/// Returns the number of instructions in the code
+	pub fn instruction_count(&self) -> usize {
+		self.instructions().count()
+	}
+
+	/// Returns the offset and opcode of every instruction
+	fn instructions(&self) -> impl Iterator<Item = (usize, RegOp)> {
+		let code = &self.chunk.code;
+		let mut offset = 0;
+		std::iter::from_fn(move || {
+			let op = RegOp::try_from(*code.get(offset)?).ok()?;
+			offset += op.size();
+			Some((offset - op.size(), op))
+		})
+	}
+
+}
+
+/// Formats the code as a listing, one instruction per line like `0000 0001 OP_ADD               r0 r0 r1`
+impl fmt::Display for RegisterChunk {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (offset, op) in self.instructions() {
+			let line = self.chunk.find_line(offset);
+			let continues_line = offset > 0 && line == self.chunk.find_line(offset - 1);
+			let location = Location { line, continues_line, columns: self.chunk.find_columns(offset) };
+			writeln!(f, "{offset:04} {location} {}", RegInstruction { chunk: &self.chunk, offset, op })?;
+		}
+		Ok(())
+	}
+
+}
+
+/// A single instruction of a register chunk, formatted as its name followed by its operands
+struct RegInstruction<'a> {
+
+	chunk: &'a Chunk,
+
+	offset: usize,
+
+	op: RegOp,
+
+}
+
+impl fmt::Display for RegInstruction<'_> {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let code = &self.chunk.code;
+		write!(f, "{:<20}", self.op.name())?;
+		let operands = self.op.operands();
+		let constant_offset = self.offset + 1 + operands.register_count();
+		for register in &code[self.offset + 1..constant_offset] {
+			write!(f, " r{register}")?;
+		}
+		if let RegOperands::RegistersAndConstant(_) = operands {
+			// Safety: the instruction has been decoded from the code, so its operands are in bounds
+			let const_id = unsafe { read_wide_operand(code.as_ptr().add(constant_offset)) };
+			write!(f, " '{}'", self.chunk.get_constant(const_id))?;
+		}
+		Ok(())
+	}
+
+}
+
+/// Where the translation keeps the value of a stack slot
+#[derive(Clone)] #[derive(Copy)]
+enum Slot {
+
+	/// The value is in the register of the slot
+	Register,
+
+	/// The value is a constant that hasn't been loaded yet, along with the location of the instruction pushing it
+	Constant(usize, Span),
+
+}
+
+/// State of translating a stack chunk, the stack is simulated by a slot per value
+struct Translator {
+
+	chunk: Chunk,
+
+	slots: Vec<Slot>,
+
+	/// Number of registers written to so far
+	register_count: usize,
+
+}
+
+impl Translator {
+
+	/// Translates a binary operation on the two topmost stack slots
+	fn binary(&mut self, op: RegOp, constant_op: RegOp, right: Slot, span: Span) {
+		let left = self.slots.len() - 1;
+		match (self.slots[left], right) {
+			// a constant left operand of a commutative operation is taken as operand instead of being loaded
+			(Slot::Constant(const_id, _), Slot::Register) if matches!(op, RegOp::Add | RegOp::Multiply) => {
+				self.slots[left] = Slot::Register;
+				self.write_with_constant(constant_op, &[ left as u8, (left + 1) as u8 ], const_id, span);
+			},
+			(_, Slot::Constant(const_id, _)) => {
+				self.load(left);
+				self.write_with_constant(constant_op, &[ left as u8, left as u8 ], const_id, span);
+			},
+			(_, Slot::Register) => {
+				self.load(left);
+				self.write(op, &[ left as u8, left as u8, (left + 1) as u8 ], span);
+			},
+		}
+	}
+
+	/// Loads the constant of a stack slot into its register, if it hasn't been loaded yet
+	fn load(&mut self, register: usize) {
+		if let Slot::Constant(const_id, span) = self.slots[register] {
+			self.write_with_constant(RegOp::LoadConstant, &[ register as u8 ], const_id, span);
+			self.slots[register] = Slot::Register;
+		}
+	}
+
+	/// Writes an instruction whose register operands are followed by a constant index
+	fn write_with_constant(&mut self, op: RegOp, registers: &[u8], const_id: usize, span: Span) {
+		let bytes = const_id.to_be_bytes();
+		self.write(op, registers, span);
+		for byte in &bytes[bytes.len() - WIDE_OPERAND_SIZE..] {
+			self.chunk.write(*byte, span.line);
+		}
+	}
+
+	/// Writes an instruction at the source location, the first operand is always a register
+	fn write(&mut self, op: RegOp, operands: &[u8], span: Span) {
+		self.register_count = self.register_count.max(operands[0] as usize + 1);
+		self.chunk.set_columns(span.columns);
+		self.chunk.write(op, span.line);
+		for operand in operands {
+			self.chunk.write(*operand, span.line);
+		}
+	}
+
+}
+
+/// Translates a stack chunk into register code, the chunk is verified first
+pub fn translate(chunk: &Chunk) -> Result<RegisterChunk, TranslateError> {
+	verifier::verify(chunk, 0, usize::MAX).map_err(TranslateError::Verify)?;
+	let mut translator = Translator { chunk: chunk.clone(), slots: Vec::new(), register_count: 0 };
+	translator.chunk.truncate(0);
+	for instruction in Listing::new(chunk).instructions {
+		let span = chunk.find_span(instruction.offset).unwrap_or_default();
+		let operand = instruction.operand.unwrap_or_default();
+		// verification guarantees known opcodes, valid operands and a stack that never underflows
+		let Ok(op) = Op::try_from(instruction.widened_opcode.unwrap_or(instruction.opcode)) else {
+			continue;
+		};
+		let (reg_op, constant_op) = match op {
+			Op::Add => (RegOp::Add, RegOp::AddConstant),
+			Op::Subtract => (RegOp::Subtract, RegOp::SubtractConstant),
+			Op::Multiply => (RegOp::Multiply, RegOp::MultiplyConstant),
+			Op::Divide => (RegOp::Divide, RegOp::DivideConstant),
+			_ => (RegOp::Return, RegOp::Return),
+		};
+		match op {
+			Op::Constant => translator.slots.push(Slot::Constant(operand, span)),
+			Op::Add | Op::Subtract | Op::Multiply | Op::Divide => {
+				let right = translator.slots.pop().unwrap_or(Slot::Register);
+				translator.binary(reg_op, constant_op, right, span);
+			},
+			Op::Negate => {
+				let register = translator.slots.len() - 1;
+				translator.load(register);
+				translator.write(RegOp::Negate, &[ register as u8, register as u8 ], span);
+			},
+			Op::Return => {
+				let register = translator.slots.len() - 1;
+				translator.load(register);
+				translator.slots.pop();
+				translator.write(RegOp::Return, &[ register as u8 ], span);
+			},
+			Op::Wide => {},
+		}
+		if translator.slots.len() > MAX_REGISTERS {
+			return Err(TranslateError::TooManyRegisters { offset: instruction.offset });
+		}
+	}
+	Ok(RegisterChunk { chunk: translator.chunk, register_count: translator.register_count })
+}
+
+/// Interpreter for register chunks
+pub struct RegisterVM {
+
+	registers: Vec<Value>,
+
+	sinks: Sinks,
+
+	trace_mode: TraceMode,
+
+	/// Only instructions on these source lines are traced, all are if `None`
+	trace_lines: Option<RangeInclusive<u32>>,
+
+}
+
+impl RegisterVM {
+
+	pub fn new() -> Self {
+		Self {
+			registers: Vec::new(),
+			sinks: Sinks::new(),
+			trace_mode: TraceMode::Off,
+			trace_lines: None,
+		}
+	}
+
+	/// Replaces the sink receiving program output, flushing the previous one
+	pub fn set_output(&mut self, writer: Box<dyn Write>) {
+		self.sinks.set_output(writer);
+	}
+
+	/// Replaces the sink receiving error reports, flushing the previous one
+	pub fn set_error_output(&mut self, writer: Box<dyn Write>) {
+		self.sinks.set_error_output(writer);
+	}
+
+	/// Selects how much of the execution is traced, tracing is off by default
+	pub fn set_trace_mode(&mut self, trace_mode: TraceMode) {
+		self.trace_mode = trace_mode;
+	}
+
+	/// Restricts tracing to instructions on the given source lines, `None` traces all lines
+	pub fn set_trace_lines(&mut self, trace_lines: Option<RangeInclusive<u32>>) {
+		self.trace_lines = trace_lines;
+	}
+
+	/// Replaces the sink receiving instruction traces, flushing the previous one
+	pub fn set_trace_output(&mut self, writer: Box<dyn Write>) {
+		self.sinks.set_trace_output(writer);
+	}
+
+	/// Writes an error that occurred outside of the VM, eg. while compiling, to the error output
+	pub fn report_error(&mut self, error: &dyn fmt::Display) {
+		self.sinks.report_error(error);
+	}
+
+	/// Translates and runs a stack chunk, chunks that can't be translated are rejected with
+	/// [InterpretError::BadChunk], chunks needing more registers than there are overflow like the stack machine
+	pub fn interpret(&mut self, chunk: &Chunk) -> Result<(), InterpretError> {
+		let interpret_error = match translate(chunk) {
+			Ok(register_chunk) => {
+				self.run(&register_chunk);
+				return Ok(());
+			},
+			Err(TranslateError::TooManyRegisters { offset }) => {
+				InterpretError::Runtime(RuntimeError::at("Stack overflow.", chunk, offset))
+			},
+			Err(translate_error) => {
+				let _ = writeln!(self.sinks.error_output, "{translate_error}");
+				InterpretError::BadChunk
+			},
+		};
+		self.sinks.report_error(&interpret_error);
+		Err(interpret_error)
+	}
+
+	/// Runs a register chunk, the registers start out as nil
+	///
+	/// Translation has already rejected everything that could fail, so running can't.
+	pub fn run(&mut self, register_chunk: &RegisterChunk) {
+		self.registers.clear();
+		self.registers.resize(register_chunk.register_count, Value::nil());
+		match self.trace_mode {
+			TraceMode::Off => self.run_code::<false>(&register_chunk.chunk),
+			_ => self.run_code::<true>(&register_chunk.chunk),
+		}
+		self.sinks.flush();
+	}
+
+	fn run_code<const TRACE: bool>(&mut self, chunk: &Chunk) {
+		let registers = self.registers.as_mut_ptr();
+		let Range { start: mut ip, end: end_ptr } = chunk.get_code_pointer_range();
+		// Safety: register chunks are only built by translate, so the code holds known opcodes followed by all of
+		// their operands, register operands are below the register count and constant indices are in the table
+		while ip < end_ptr {
+			let op_ptr = ip;
+			let op = unsafe { RegOp::from_u8_unchecked(*ip) };
+			if TRACE {
+				self.trace_op(chunk, op_ptr, op);
+			}
+			unsafe { ip = ip.add(op.size()); }
+			match op {
+				RegOp::LoadConstant => unsafe {
+					*register(registers, op_ptr, 1) = *chunk.get_constant(read_wide_operand(op_ptr.add(2)));
+				},
+				RegOp::Add => unsafe { binary_op(registers, op_ptr, |left, right| left.add(right)) },
+				RegOp::Subtract => unsafe { binary_op(registers, op_ptr, |left, right| left.subtract(right)) },
+				RegOp::Multiply => unsafe { binary_op(registers, op_ptr, |left, right| left.multiply(right)) },
+				RegOp::Divide => unsafe { binary_op(registers, op_ptr, |left, right| left.divide(right)) },
+				RegOp::AddConstant => unsafe { constant_op(registers, chunk, op_ptr, Value::add) },
+				RegOp::SubtractConstant => unsafe { constant_op(registers, chunk, op_ptr, Value::subtract) },
+				RegOp::MultiplyConstant => unsafe { constant_op(registers, chunk, op_ptr, Value::multiply) },
+				RegOp::DivideConstant => unsafe { constant_op(registers, chunk, op_ptr, Value::divide) },
+				RegOp::Negate => unsafe {
+					let mut value = *register(registers, op_ptr, 2);
+					value.negate();
+					*register(registers, op_ptr, 1) = value;
+				},
+				RegOp::Return => {
+					let _ = writeln!(self.sinks.output, "{}", unsafe { *register(registers, op_ptr, 1) });
+				},
+			}
+		}
+	}
+
+	#[cold]
+	fn trace_op(&mut self, chunk: &Chunk, op_ptr: *const u8, op: RegOp) {
+		// Safety: op_ptr always points into the code of the chunk being run
+		let offset = unsafe { op_ptr.offset_from(chunk.code.as_ptr()) } as usize;
+		let line = chunk.find_line(offset);
+		if !is_traced_line(&self.trace_lines, line) {
+			return;
+		}
+		if self.trace_mode == TraceMode::OpsAndStack {
+			let _ = write!(self.sinks.trace_output, "          ");
+			for value in &self.registers {
+				let _ = write!(self.sinks.trace_output, "[ {value} ]");
+			}
+			let _ = writeln!(self.sinks.trace_output);
+		}
+		let location = Location { line, continues_line: false, columns: chunk.find_columns(offset) };
+		let _ = writeln!(self.sinks.trace_output, "{offset:04} {location} {}", RegInstruction { chunk, offset, op });
+	}
+
+}
+
+impl Backend for RegisterVM {
+
+	fn interpret(&mut self, chunk: &Chunk) -> Result<(), InterpretError> {
+		RegisterVM::interpret(self, chunk)
+	}
+
+	fn set_output(&mut self, writer: Box<dyn Write>) {
+		RegisterVM::set_output(self, writer);
+	}
+
+	fn set_error_output(&mut self, writer: Box<dyn Write>) {
+		RegisterVM::set_error_output(self, writer);
+	}
+
+	fn set_trace_mode(&mut self, trace_mode: TraceMode) {
+		RegisterVM::set_trace_mode(self, trace_mode);
+	}
+
+	fn set_trace_lines(&mut self, trace_lines: Option<RangeInclusive<u32>>) {
+		RegisterVM::set_trace_lines(self, trace_lines);
+	}
+
+	fn set_trace_output(&mut self, writer: Box<dyn Write>) {
+		RegisterVM::set_trace_output(self, writer);
+	}
+
+	fn report_error(&mut self, error: &dyn fmt::Display) {
+		RegisterVM::report_error(self, error);
+	}
+
+}
+
+impl Default for RegisterVM {
+
+	fn default() -> Self {
+		Self::new()
+	}
+
+}
+
+/// Returns a pointer to the register named by the operand byte at `operand` bytes past `op_ptr`
+///
+/// # Safety
+/// The operand byte must be in bounds and name one of the registers starting at `registers`
+#[inline]
+unsafe fn register(registers: *mut Value, op_ptr: *const u8, operand: usize) -> *mut Value {
+	unsafe { registers.add(*op_ptr.add(operand) as usize) }
+}
+
+/// Runs `dst = left op right` for the `dst, left, right` operands of the instruction at `op_ptr`
+///
+/// # Safety
+/// See [register]
+#[inline]
+unsafe fn binary_op(registers: *mut Value, op_ptr: *const u8, operation: impl FnOnce(&mut Value, &Value)) {
+	unsafe {
+		let mut value = *register(registers, op_ptr, 2);
+		operation(&mut value, &*register(registers, op_ptr, 3));
+		*register(registers, op_ptr, 1) = value;
+	}
+}
+
+/// Runs `dst = left op constant` for the `dst, left, constant` operands of the instruction at `op_ptr`
+///
+/// # Safety
+/// See [register], the constant index must also be in bounds
+#[inline]
+unsafe fn constant_op(registers: *mut Value, chunk: &Chunk, op_ptr: *const u8, operation: impl FnOnce(&mut Value, &Value)) {
+	unsafe {
+		let mut value = *register(registers, op_ptr, 2);
+		operation(&mut value, chunk.get_constant(read_wide_operand(op_ptr.add(3))));
+		*register(registers, op_ptr, 1) = value;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm::assemble;
+	use crate::compiler;
+	use crate::compiler::CompilerOptions;
+	use crate::output::MemorySink;
+	use crate::vm::VM;
+	use crate::vm::tests::traced_chunk;
+
+	/// Programs both backends have to agree on
+	const PROGRAMS: [&str; 16] = [
+		"1",
+		"-2.5",
+		"-0",
+		"(((1)))",
+		"1 + 2 * 3",
+		"1 - 2 - 3",
+		"8 / 2 / 2",
+		"2 * -3",
+		"(1 + 2) * (3 - 4) / 5",
+		"--1",
+		"-(-(-4))",
+		"-(1 - -(2 * -3))",
+		"0 / 0",
+		"1 / 0 - 1 / 0",
+		"1 + 2 + 3 + 4 + 5 + 6 + 7 + 8",
+		"2 * (3 + (4 * (5 - (6 / (7 + 8)))))",
+	];
+
+	fn run_stack(chunk: &Chunk) -> (Result<(), InterpretError>, String) {
+		let output = MemorySink::new();
+		let mut vm = VM::<64>::new();
+		vm.set_output(Box::new(output.clone()));
+		(vm.interpret(chunk), output.contents())
+	}
+
+	fn run_registers(chunk: &Chunk) -> (Result<(), InterpretError>, String) {
+		let output = MemorySink::new();
+		let mut vm = RegisterVM::new();
+		vm.set_output(Box::new(output.clone()));
+		vm.set_error_output(Box::new(std::io::sink()));
+		(vm.interpret(chunk), output.contents())
+	}
+
+	#[test]
+	fn backends_should_agree() {
+		let options = [
+			CompilerOptions::default(),
+			CompilerOptions { fold_constants: false, peephole: false },
+			CompilerOptions { fold_constants: false, peephole: true },
+		];
+		for program in PROGRAMS {
+			for options in &options {
+				let chunk = compiler::compile_with_options(program, options.clone()).unwrap();
+
+				let registers = run_registers(&chunk);
+
+				assert_eq!(registers, run_stack(&chunk), "{program} {options:?}");
+			}
+		}
+	}
+
+	#[test]
+	fn backends_should_agree_on_widened_constants() {
+		let program = (0..300).map(|i| format!("{i}.5")).collect::<Vec<_>>().join(" - ");
+		let chunk = compiler::compile_with_options(&program, CompilerOptions { fold_constants: false, peephole: true }).unwrap();
+
+		let registers = run_registers(&chunk);
+
+		assert_eq!(registers, run_stack(&chunk));
+	}
+
+	#[test]
+	fn interpret_should_trace_ops() {
+		let trace_output = MemorySink::new();
+		let mut sut = RegisterVM::new();
+		sut.set_output(Box::new(MemorySink::new()));
+		sut.set_trace_output(Box::new(trace_output.clone()));
+		sut.set_trace_mode(TraceMode::Ops);
+
+		let _ = sut.interpret(&traced_chunk());
+
+		assert_eq!(trace_output.contents(), "\
+			0000 0001 OP_LOAD_CONSTANT     r0 '1'\n\
+			0005 0002 OP_NEGATE            r0 r0\n\
+			0008 0003 OP_RETURN            r0\n");
+	}
+
+	#[test]
+	fn interpret_should_trace_ops_and_registers() {
+		let trace_output = MemorySink::new();
+		let mut sut = RegisterVM::new();
+		sut.set_output(Box::new(MemorySink::new()));
+		sut.set_trace_output(Box::new(trace_output.clone()));
+		sut.set_trace_mode(TraceMode::OpsAndStack);
+
+		let _ = sut.interpret(&traced_chunk());
+
+		assert_eq!(trace_output.contents(), "          [ nil ]\n\
+			0000 0001 OP_LOAD_CONSTANT     r0 '1'\n\
+			\x20         [ 1 ]\n\
+			0005 0002 OP_NEGATE            r0 r0\n\
+			\x20         [ -1 ]\n\
+			0008 0003 OP_RETURN            r0\n");
+	}
+
+	#[test]
+	fn try_from_should_decode_every_instruction() {
+		for op in RegOp::ALL {
+			assert_eq!(RegOp::try_from(*op as u8), Ok(*op));
+		}
+		assert_eq!(RegOp::try_from(0xff), Err(0xff));
+	}
+
+	#[test]
+	fn translate_should_take_constants_as_operands() {
+		let chunk = assemble("1 OP_CONSTANT 1\n| OP_CONSTANT 2\n| OP_CONSTANT 3\n2 OP_MULTIPLY\n| OP_SUBTRACT\n| OP_RETURN").unwrap();
+
+		let sut = translate(&chunk).unwrap();
+
+		assert_eq!(sut.to_string(), "\
+			0000 0001 OP_LOAD_CONSTANT     r1 '2'\n\
+			0005 0002 OP_MULTIPLY_CONSTANT r1 r1 '3'\n\
+			0011 0001 OP_LOAD_CONSTANT     r0 '1'\n\
+			0016 0002 OP_SUBTRACT          r0 r0 r1\n\
+			0020    | OP_RETURN            r0\n");
+		assert_eq!(sut.register_count(), 2);
+	}
+
+	#[test]
+	fn translate_should_swap_constant_operands_of_commutative_operations() {
+		let chunk = assemble("OP_CONSTANT 1\nOP_CONSTANT 2\nOP_NEGATE\nOP_ADD\nOP_RETURN").unwrap();
+
+		let sut = translate(&chunk).unwrap();
+
+		assert_eq!(sut.to_string(), "\
+			0000 0001 OP_LOAD_CONSTANT     r1 '2'\n\
+			0005    | OP_NEGATE            r1 r1\n\
+			0008    | OP_ADD_CONSTANT      r0 r1 '1'\n\
+			0014    | OP_RETURN            r0\n");
+		assert_eq!(sut.instruction_count(), 4);
+	}
+
+	#[test]
+	fn translate_should_take_wide_constants_as_operands() {
+		let mut chunk = Chunk::new();
+		for _ in 0..256 {
+			chunk.add_constant(Value::new(0.0)).unwrap();
+		}
+		chunk.write_constant(Value::new(2.0), 1).unwrap();
+		chunk.write_with_operand(Op::Constant, 0, 1).unwrap();
+		chunk.write(Op::Subtract, 1);
+		chunk.write_with_operand(Op::Constant, 256, 1).unwrap();
+		chunk.write(Op::Divide, 1);
+		chunk.write(Op::Return, 1);
+
+		let sut = translate(&chunk).unwrap();
+
+		assert_eq!(sut.to_string(), "\
+			0000 0001 OP_LOAD_CONSTANT     r0 '2'\n\
+			0005    | OP_SUBTRACT_CONSTANT r0 r0 '0'\n\
+			0011    | OP_DIVIDE_CONSTANT   r0 r0 '2'\n\
+			0017    | OP_RETURN            r0\n");
+		assert_eq!(run_registers(&chunk).1, "1\n");
+	}
+
+	#[test]
+	fn translate_should_reject_malformed_chunk() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Add, 1);
+
+		let sut = translate(&chunk);
+
+		assert_eq!(sut.err().unwrap(), TranslateError::Verify(VerifyError::StackUnderflow { offset: 0 }));
+	}
+
+	#[test]
+	fn translate_should_reject_chunk_needing_too_many_registers() {
+		let mut chunk = Chunk::new();
+		for _ in 0..300 {
+			chunk.write_constant(Value::new(1.0), 1).unwrap();
+		}
+		for _ in 0..299 {
+			chunk.write(Op::Add, 1);
+		}
+		chunk.write(Op::Return, 1);
+
+		let sut = translate(&chunk);
+
+		assert!(matches!(sut.err().unwrap(), TranslateError::TooManyRegisters { .. }));
+	}
+
+	#[test]
+	fn interpret_should_report_bad_chunk() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Return, 1);
+
+		let (result, output) = run_registers(&chunk);
+
+		assert_eq!(result, Err(InterpretError::BadChunk));
+		assert_eq!(output, "");
+	}
+
+}
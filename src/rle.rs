@@ -1,4 +1,37 @@
+//! Run-length encoded sequences, used for the line and column tables of chunks
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::chunk::Columns;
+
+/// Number of runs between two checkpoints of a [ColumnTable], lookups decode at most this many runs
+const CHECKPOINT_INTERVAL: usize = 32;
+
+/// Header byte of a [ColumnTable] run whose length and width don't fit into the header, so they follow it
+const LONG_RUN: u8 = 0x80;
+
+/// Number of runs in a [RunLengthEncoder] and the heap memory they take
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct MemoryUsage {
+
+	pub runs: usize,
+
+	pub bytes: usize,
+
+}
+
+impl fmt::Display for MemoryUsage {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} runs, {} bytes", self.runs, self.bytes)
+	}
+
+}
+
+#[derive(Clone)]
 pub struct RunLengthEncoder<T: PartialEq> {
+	/// Value of each run and the position just past its end, the length of a run is the distance to the previous end
 	values: Vec<(T, u32)>,
 }
 
@@ -10,26 +43,290 @@ impl<T: PartialEq> RunLengthEncoder<T> {
 
 	pub fn add(&mut self, value: T) {
 		match self.values.last_mut() {
-			Some(v) if v.0 == value => v.1 += 1,
-			_ => self.values.push((value, 1))
+			Some((last_value, end)) if *last_value == value => *end += 1,
+			_ => {
+				let end = self.len() as u32 + 1;
+				self.values.push((value, end));
+			},
+		};
+	}
+
+	/// Keeps only the first `len` positions, dropping the rest
+	pub fn truncate(&mut self, len: usize) {
+		let kept_runs = self.values.partition_point(|(_, end)| (*end as usize) < len);
+		let kept_runs = match kept_runs < self.values.len() && len > 0 {
+			true => kept_runs + 1,
+			false => kept_runs,
 		};
+		self.values.truncate(kept_runs);
+		if let Some((_, last_end)) = self.values.last_mut() {
+			*last_end = (*last_end).min(len as u32);
+		}
+	}
+
+	/// Returns every run as its value and the number of positions it covers
+	pub fn runs(&self) -> impl Iterator<Item = (&T, u32)> {
+		self.ranges().map(|(value, range)| (value, range.len() as u32))
 	}
 
+	/// Returns every run as its value and the range of positions it covers
+	pub fn ranges(&self) -> impl Iterator<Item = (&T, Range<usize>)> {
+		let starts = std::iter::once(0).chain(self.values.iter().map(|(_, end)| *end as usize));
+		self.values.iter().zip(starts).map(|((value, end), start)| (value, start..*end as usize))
+	}
+
+	/// Returns the value at the given position, or `None` if the position is past the end
 	pub fn find(&self, position: usize) -> Option<&T> {
-		let mut lower_bound: usize = 0;
-		for tuple in &self.values {
-			let upper_bound = lower_bound + tuple.1 as usize;
-			if position >= lower_bound && position < upper_bound {
-				return Some(&tuple.0);
-			} else {
-				lower_bound += tuple.1 as usize;
-			}
+		let run = self.values.partition_point(|(_, end)| (*end as usize) <= position);
+		self.values.get(run).map(|(value, _)| value)
+	}
+
+	/// Returns the number of runs and the heap memory allocated for them
+	pub fn memory_usage(&self) -> MemoryUsage {
+		MemoryUsage {
+			runs: self.values.len(),
+			bytes: self.values.capacity() * std::mem::size_of::<(T, u32)>(),
 		}
-		None
+	}
+
+	/// Returns the number of positions covered by the runs
+	fn len(&self) -> usize {
+		self.values.last().map_or(0, |(_, end)| *end as usize)
 	}
 
 }
 
+impl<T: PartialEq> Default for RunLengthEncoder<T> {
+
+	fn default() -> Self {
+		Self::new()
+	}
+
+}
+
+/// A run of a [ColumnTable] along with the position it starts at
+#[derive(Clone)] #[derive(Copy)]
+struct ColumnRun {
+
+	columns: Columns,
+
+	position: u32,
+
+	length: u32,
+
+}
+
+impl ColumnRun {
+
+	fn end(&self) -> usize {
+		(self.position + self.length) as usize
+	}
+
+}
+
+/// Where decoding a [ColumnTable] can start from
+#[derive(Clone)] #[derive(Copy)] #[derive(Default)]
+struct Checkpoint {
+
+	/// Position the first run after the checkpoint starts at
+	position: u32,
+
+	/// Index of the byte the first run after the checkpoint is encoded at
+	byte: u32,
+
+	/// Start column of the run before the checkpoint, which the first run after it is encoded relative to
+	base: u16,
+
+}
+
+/// Run-length encoded columns, taking a few bytes per run as there is about one run per instruction
+///
+/// Every run is encoded as a header byte holding its length and width, followed by the distance of its start
+/// column from the start column of the previous run as a variable-length integer. Runs longer than 8 positions or
+/// wider than 15 columns put [LONG_RUN] into the header and follow it with their length and width. A checkpoint
+/// every [CHECKPOINT_INTERVAL] runs lets lookups skip to the run they need, and the last run is kept decoded so it
+/// can still grow.
+#[derive(Clone)]
+pub struct ColumnTable {
+
+	bytes: Vec<u8>,
+
+	checkpoints: Vec<Checkpoint>,
+
+	/// Number of runs encoded into `bytes`
+	encoded_runs: usize,
+
+	/// Start column of the last encoded run
+	last_start: u16,
+
+	/// The last run, encoded once a run with other columns is added
+	pending: Option<ColumnRun>,
+
+}
+
+impl ColumnTable {
+
+	pub fn new() -> Self {
+		Self { bytes: Vec::new(), checkpoints: Vec::new(), encoded_runs: 0, last_start: 0, pending: None }
+	}
+
+	pub fn add(&mut self, columns: Columns) {
+		match &mut self.pending {
+			Some(run) if run.columns == columns => run.length += 1,
+			pending => {
+				let position = pending.map_or(0, |run| run.end() as u32);
+				if let Some(run) = pending.take() {
+					self.encode(run);
+				}
+				self.pending = Some(ColumnRun { columns, position, length: 1 });
+			},
+		}
+	}
+
+	/// Keeps only the first `len` positions, dropping the rest
+	pub fn truncate(&mut self, len: usize) {
+		let Some(pending) = &mut self.pending else {
+			return;
+		};
+		if len > pending.position as usize {
+			pending.length = pending.length.min(len as u32 - pending.position);
+			return;
+		}
+		if len == 0 {
+			*self = Self::new();
+			return;
+		}
+		// the run holding the last kept position is decoded again, so it can grow like the last run
+		let checkpoint = self.checkpoint_before(len - 1);
+		let found = self.decode(self.checkpoints[checkpoint]).enumerate().find(|(_, (_, _, run))| len <= run.end());
+		let Some((index, (byte, base, run))) = found else {
+			unreachable!("checkpoints only point at encoded runs");
+		};
+		self.encoded_runs = checkpoint * CHECKPOINT_INTERVAL + index;
+		self.bytes.truncate(byte);
+		self.checkpoints.truncate(self.encoded_runs.div_ceil(CHECKPOINT_INTERVAL));
+		self.last_start = base;
+		self.pending = Some(ColumnRun { length: len as u32 - run.position, ..run });
+	}
+
+	/// Returns every run as its columns and the number of positions it covers
+	pub fn runs(&self) -> impl Iterator<Item = (Columns, u32)> {
+		self.decode(Checkpoint::default())
+			.map(|(_, _, run)| run)
+			.chain(self.pending)
+			.map(|run| (run.columns, run.length))
+	}
+
+	/// Returns the columns at the given position, or `None` if the position is past the end
+	pub fn find(&self, position: usize) -> Option<Columns> {
+		let pending = self.pending?;
+		if position >= pending.position as usize {
+			return (position < pending.end()).then_some(pending.columns);
+		}
+		self.decode(self.checkpoints[self.checkpoint_before(position)])
+			.map(|(_, _, run)| run)
+			.find(|run| position < run.end())
+			.map(|run| run.columns)
+	}
+
+	/// Returns the number of runs and the heap memory allocated for them
+	pub fn memory_usage(&self) -> MemoryUsage {
+		MemoryUsage {
+			runs: self.encoded_runs + usize::from(self.pending.is_some()),
+			bytes: self.bytes.capacity() + self.checkpoints.capacity() * std::mem::size_of::<Checkpoint>(),
+		}
+	}
+
+	/// Returns the index of the last checkpoint at or before an encoded position
+	fn checkpoint_before(&self, position: usize) -> usize {
+		self.checkpoints.partition_point(|checkpoint| checkpoint.position as usize <= position) - 1
+	}
+
+	fn encode(&mut self, run: ColumnRun) {
+		if self.encoded_runs.is_multiple_of(CHECKPOINT_INTERVAL) {
+			self.checkpoints.push(Checkpoint { position: run.position, byte: self.bytes.len() as u32, base: self.last_start });
+		}
+		let Columns { start, end } = run.columns;
+		let width = end.wrapping_sub(start);
+		match (run.length, width) {
+			(1..=8, 0..=15) => self.bytes.push((run.length - 1) as u8 | (width as u8) << 3),
+			_ => {
+				self.bytes.push(LONG_RUN);
+				write_varint(&mut self.bytes, run.length);
+				write_varint(&mut self.bytes, u32::from(width));
+			},
+		}
+		write_varint(&mut self.bytes, zigzag(i32::from(start) - i32::from(self.last_start)));
+		self.last_start = start;
+		self.encoded_runs += 1;
+	}
+
+	/// Decodes the runs encoded from the checkpoint on, along with the byte each run is encoded at and the start
+	/// column it is encoded relative to
+	fn decode(&self, checkpoint: Checkpoint) -> impl Iterator<Item = (usize, u16, ColumnRun)> {
+		let Checkpoint { mut position, byte, mut base } = checkpoint;
+		let mut index = byte as usize;
+		std::iter::from_fn(move || {
+			let byte = index;
+			let header = *self.bytes.get(index)?;
+			index += 1;
+			let (length, width) = match header {
+				LONG_RUN => (read_varint(&self.bytes, &mut index)?, read_varint(&self.bytes, &mut index)? as u16),
+				_ => (u32::from(header & 0x07) + 1, u16::from(header >> 3)),
+			};
+			let start = (i32::from(base) + unzigzag(read_varint(&self.bytes, &mut index)?)) as u16;
+			let end = start.wrapping_add(width);
+			let run = ColumnRun { columns: Columns { start, end }, position, length };
+			let previous_base = base;
+			base = start;
+			position += length;
+			Some((byte, previous_base, run))
+		})
+	}
+
+}
+
+impl Default for ColumnTable {
+
+	fn default() -> Self {
+		Self::new()
+	}
+
+}
+
+/// Writes the value seven bits at a time, the high bit of every byte but the last is set
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+	while value >= 0x80 {
+		bytes.push(value as u8 | 0x80);
+		value >>= 7;
+	}
+	bytes.push(value as u8);
+}
+
+/// Reads a value written by [write_varint] and advances the index past it, `None` at the end of the bytes
+fn read_varint(bytes: &[u8], index: &mut usize) -> Option<u32> {
+	let mut value = 0;
+	let mut shift = 0;
+	loop {
+		let byte = *bytes.get(*index)?;
+		*index += 1;
+		value |= u32::from(byte & 0x7f) << shift;
+		if byte < 0x80 {
+			return Some(value);
+		}
+		shift += 7;
+	}
+}
+
+/// Maps signed values to unsigned ones so small negative values stay small: 0, -1, 1, -2 become 0, 1, 2, 3
+fn zigzag(value: i32) -> u32 {
+	((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+	(value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -43,7 +340,7 @@ mod tests {
 
 		assert_eq!(sut.values.len(), 1);
 		assert_eq!(sut.values.last().unwrap().0, 1);
-		assert_eq!(sut.values.last().unwrap().1, 2);
+		assert_eq!(sut.runs().last(), Some((&1, 2)));
 	}
 
 	#[test]
@@ -54,8 +351,7 @@ mod tests {
 		sut.add(2);
 
 		assert_eq!(sut.values.len(), 2);
-		assert_eq!(sut.values[0].1, 1);
-		assert_eq!(sut.values[1].1, 1);
+		assert_eq!(sut.runs().collect::<Vec<_>>(), [ (&1, 1), (&2, 1) ]);
 	}
 
 	#[test]
@@ -85,4 +381,135 @@ mod tests {
 		assert!(result.is_none());
 	}
 
+	#[test]
+	fn truncate_should_drop_positions_past_len() {
+		let mut sut = RunLengthEncoder::<u32>::new();
+		for value in [ 1, 1, 2, 2, 2, 3 ] {
+			sut.add(value);
+		}
+
+		sut.truncate(3);
+
+		assert_eq!(sut.runs().map(|(value, length)| (*value, length)).collect::<Vec<_>>(), [ (1, 2), (2, 1) ]);
+		assert_eq!(sut.find(3), None);
+	}
+
+	#[test]
+	fn find_should_locate_every_position_of_many_runs() {
+		let mut sut = RunLengthEncoder::<u32>::new();
+		let mut expected = Vec::new();
+		for line in 0..1000 {
+			for _ in 0..(line % 4 + 1) {
+				sut.add(line);
+				expected.push(line);
+			}
+		}
+
+		let found: Vec<_> = (0..expected.len()).map(|position| sut.find(position).copied()).collect();
+
+		assert!(found.iter().zip(&expected).all(|(found, expected)| *found == Some(*expected)));
+		assert_eq!(sut.find(expected.len()), None);
+	}
+
+	#[test]
+	fn truncate_should_keep_lookups_consistent() {
+		let mut sut = RunLengthEncoder::<u32>::new();
+		for value in [ 1, 1, 2, 2, 2, 3 ] {
+			sut.add(value);
+		}
+
+		sut.truncate(2);
+		sut.add(4);
+
+		assert_eq!(sut.ranges().map(|(value, range)| (*value, range)).collect::<Vec<_>>(), [ (1, 0..2), (4, 2..3) ]);
+		assert_eq!(sut.find(2), Some(&4));
+	}
+
+	#[test]
+	fn memory_usage_should_count_runs() {
+		let mut sut = RunLengthEncoder::<u32>::new();
+		for value in [ 1, 1, 2 ] {
+			sut.add(value);
+		}
+
+		let usage = sut.memory_usage();
+
+		assert_eq!(usage.runs, 2);
+		assert_eq!(usage.to_string(), format!("2 runs, {} bytes", 8 * 8));
+	}
+
+	fn column_runs(runs: &[(usize, usize, u32)]) -> Vec<(Columns, u32)> {
+		runs.iter().map(|(start, end, length)| (Columns::new(*start, *end), *length)).collect()
+	}
+
+	fn column_table(runs: &[(Columns, u32)]) -> ColumnTable {
+		let mut sut = ColumnTable::new();
+		for (columns, length) in runs {
+			for _ in 0..*length {
+				sut.add(*columns);
+			}
+		}
+		sut
+	}
+
+	#[test]
+	fn runs_should_return_added_columns() {
+		// unknown columns, columns going backwards, reversed columns and runs too long or wide for the header byte
+		// have to survive the encoding
+		let runs = column_runs(&[ (5, 9, 2), (0, 0, 1), (70_000, 70_001, 3), (1, 2, 20), (9, 4, 2), (300, 400, 1) ]);
+
+		let sut = column_table(&runs);
+
+		assert_eq!(sut.runs().collect::<Vec<_>>(), runs);
+	}
+
+	#[test]
+	fn find_should_locate_every_position_of_many_column_runs() {
+		let runs: Vec<_> = (0..1000).map(|i| (Columns::new(i % 80 + 1, i % 80 + i % 3 + 2), i as u32 % 4 + 1)).collect();
+		let sut = column_table(&runs);
+		let expected: Vec<_> = runs.iter().flat_map(|(columns, length)| std::iter::repeat_n(*columns, *length as usize)).collect();
+
+		let found: Vec<_> = (0..expected.len()).map(|position| sut.find(position)).collect();
+
+		assert!(found.iter().zip(&expected).all(|(found, expected)| *found == Some(*expected)));
+		assert_eq!(sut.find(expected.len()), None);
+	}
+
+	#[test]
+	fn truncate_should_reopen_encoded_column_run() {
+		let runs: Vec<_> = (0..40).map(|i| (Columns::new(i + 1, i + 2), 2)).collect();
+		let mut sut = column_table(&runs);
+
+		sut.truncate(35);
+		sut.add(Columns::new(18, 19));
+		sut.add(Columns::new(50, 60));
+
+		let mut expected = runs[..17].to_vec();
+		expected.push((Columns::new(18, 19), 2));
+		expected.push((Columns::new(50, 60), 1));
+		assert_eq!(sut.runs().collect::<Vec<_>>(), expected);
+		assert_eq!(sut.find(36), Some(Columns::new(50, 60)));
+		assert_eq!(sut.memory_usage().runs, 19);
+	}
+
+	#[test]
+	fn truncate_should_clear_column_table() {
+		let mut sut = column_table(&column_runs(&[ (1, 2, 2), (3, 4, 1) ]));
+
+		sut.truncate(0);
+
+		assert_eq!(sut.runs().count(), 0);
+		assert_eq!(sut.find(0), None);
+	}
+
+	#[test]
+	fn memory_usage_should_take_a_few_bytes_per_column_run() {
+		let runs: Vec<_> = (0..10_000).map(|i| (Columns::new(i % 100 + 1, i % 100 + 4), 3)).collect();
+
+		let usage = column_table(&runs).memory_usage();
+
+		assert_eq!(usage.runs, 10_000);
+		assert!(usage.bytes < 10_000 * 4, "{usage}");
+	}
+
 }
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)]
 pub enum TokenKind {
 
 	/* single character tokens */
@@ -87,8 +87,12 @@ pub enum TokenKind {
 
 	Error,
 
+	/// Never produced by the scanner, marks the end of the source for the compiler
+	Eof,
+
 }
 
+#[derive(Clone)] #[derive(Copy)]
 pub struct Token<'a> {
 
 	pub kind: TokenKind,
@@ -97,6 +101,9 @@ pub struct Token<'a> {
 
 	pub line: u32,
 
+	/// 1-based byte column where the token starts on its line, 0 for tokens not read from the source
+	pub column: u32,
+
 }
 
 pub struct Scanner<'a> {
@@ -109,6 +116,12 @@ pub struct Scanner<'a> {
 
 	line: u32,
 
+	/// Offset of the first byte of the current line
+	line_start: usize,
+
+	/// Column of the token being scanned
+	start_column: u32,
+
 }
 
 impl<'a> Scanner<'a> {
@@ -119,15 +132,18 @@ impl<'a> Scanner<'a> {
 			start: 0,
 			current: 0,
 			line: 1,
+			line_start: 0,
+			start_column: 1,
 		}
 	}
 
 	fn make_token(&self, kind: TokenKind) -> Token<'a> {
 		Token {
-			kind: kind,
+			kind,
 			// Safety: invalid sequences will already have been rejected before this point is reached
 			content: unsafe { std::str::from_utf8_unchecked(&self.source[self.start..self.current]) },
 			line: self.line,
+			column: self.start_column,
 		}
 	}
 
@@ -136,6 +152,7 @@ impl<'a> Scanner<'a> {
 			kind: TokenKind::Error,
 			content: message,
 			line: self.line,
+			column: self.start_column,
 		}
 	}
 
@@ -168,10 +185,12 @@ impl<'a> Scanner<'a> {
 
 	fn consume_string(&mut self) -> Token<'a> {
 		while self.peek() != '"' && !self.is_at_end() {
-			if self.peek() == '\n' {
+			let newline = self.peek() == '\n';
+			self.advance();
+			if newline {
 				self.line += 1;
+				self.line_start = self.current;
 			}
-			self.advance();
 		}
 		if self.is_at_end() {
 			return self.error_token("Unterminated string.");
@@ -202,6 +221,7 @@ impl<'a> Scanner<'a> {
 				'\n' => {
 					self.line += 1;
 					self.advance();
+					self.line_start = self.current;
 				},
 				'/' => {
 					if self.peek_next() == Some('/') {
@@ -230,10 +250,7 @@ impl<'a> Scanner<'a> {
 	}
 
 	fn peek_next(&self) -> Option<char> {
-		match self.source.get(self.current + 1) {
-			Some(byte) => Some(*byte as char),
-			None => None
-		}
+		self.source.get(self.current + 1).map(|byte| *byte as char)
 	}
 
 	fn advance(&mut self) {
@@ -263,6 +280,7 @@ impl<'a> Iterator for Scanner<'a> {
 	fn next(&mut self) -> Option<Self::Item> {
 		self.skip_whitespace();
 		self.start = self.current;
+		self.start_column = (self.start - self.line_start + 1) as u32;
 
 		if self.is_at_end() {
 			return None;
@@ -313,17 +331,11 @@ fn is_digit_in_option(char_option: Option<char>) -> bool {
 }
 
 fn is_digit(character: char) -> bool {
-	match character {
-		'0'..='9' => true,
-		_ => false
-	}
+	character.is_ascii_digit()
 }
 
 fn is_alpha(character: char) -> bool {
-	match character {
-		'a'..='z' | 'A'..='Z' | '_' => true,
-		_ => false
-	}
+	matches!(character, 'a'..='z' | 'A'..='Z' | '_')
 }
 
 #[cfg(test)]
@@ -358,7 +370,7 @@ mod tests {
 		assert!(matches!(sut.next().unwrap().kind, TokenKind::String));
 		assert!(matches!(sut.next().unwrap().kind, TokenKind::Number));
 		assert!(matches!(sut.next().unwrap().kind, TokenKind::Identifier));
-		assert!(matches!(sut.next(), None));
+		assert!(sut.next().is_none());
 	}
 
 	#[test]
@@ -373,7 +385,7 @@ mod tests {
 		assert!(matches!(sut.next().unwrap().kind, TokenKind::Class));
 		assert!(matches!(sut.next().unwrap().kind, TokenKind::Identifier));
 		assert!(matches!(sut.next().unwrap().kind, TokenKind::Var));
-		assert!(matches!(sut.next(), None));
+		assert!(sut.next().is_none());
 	}
 
 	#[test]
@@ -385,7 +397,16 @@ mod tests {
 		assert_eq!(sut.next().unwrap().line, 1);
 		assert_eq!(sut.next().unwrap().line, 2);
 		assert_eq!(sut.next().unwrap().line, 4);
-		assert!(matches!(sut.next(), None));
+		assert!(sut.next().is_none());
+	}
+
+	#[test]
+	fn next_should_return_tokens_with_columns() {
+		let source = "1 +\n  (23 * 4) -";
+
+		let columns: Vec<_> = Scanner::new(source).map(|token| (token.line, token.column)).collect();
+
+		assert_eq!(columns, [ (1, 1), (1, 3), (2, 3), (2, 4), (2, 7), (2, 9), (2, 10), (2, 12) ]);
 	}
 
 	#[test]
@@ -398,7 +419,7 @@ mod tests {
 		assert_eq!(sut.next().unwrap().content, "1");
 		assert_eq!(sut.next().unwrap().content, "1.0");
 		assert_eq!(sut.next().unwrap().content, "identifier");
-		assert!(matches!(sut.next(), None));
+		assert!(sut.next().is_none());
 	}
 
 }
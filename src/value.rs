@@ -1,36 +1,188 @@
+//! Values of the language, packed into a single NaN-boxed word with the `nan_boxing` feature
+
 use std::fmt;
 
+#[cfg(not(feature = "nan_boxing"))]
+#[derive(Clone)] #[derive(Copy)]
+pub struct Value(Repr);
+
+#[cfg(not(feature = "nan_boxing"))]
 #[derive(Clone)] #[derive(Copy)]
-pub struct Value {
+enum Repr {
+	Nil,
+	Bool(bool),
+	Number(f64),
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+impl Value {
+
+	pub const fn new(value: f64) -> Self {
+		Self(Repr::Number(value))
+	}
+
+	pub const fn nil() -> Self {
+		Self(Repr::Nil)
+	}
+
+	pub const fn boolean(value: bool) -> Self {
+		Self(Repr::Bool(value))
+	}
+
+	pub const fn is_nil(&self) -> bool {
+		matches!(self.0, Repr::Nil)
+	}
+
+	/// Returns the boolean, or `None` if the value isn't a boolean
+	pub const fn as_bool(&self) -> Option<bool> {
+		match self.0 {
+			Repr::Bool(value) => Some(value),
+			_ => None,
+		}
+	}
+
+	/// Returns the number, or `None` if the value isn't a number
+	pub const fn as_number(&self) -> Option<f64> {
+		match self.0 {
+			Repr::Number(value) => Some(value),
+			_ => None,
+		}
+	}
+
+}
+
+#[cfg(feature = "nan_boxing")]
+#[derive(Clone)] #[derive(Copy)]
+pub struct Value(u64);
+
+/// Bits set in every quiet NaN used as a tag: the exponent, the quiet bit and one more to avoid the
+/// "QNaN Floating-Point Indefinite" produced by x86 arithmetic
+#[cfg(feature = "nan_boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
 
-	pub value: f64,
+#[cfg(feature = "nan_boxing")]
+const TAG_NIL: u64 = 1;
+
+#[cfg(feature = "nan_boxing")]
+const TAG_FALSE: u64 = 2;
+
+#[cfg(feature = "nan_boxing")]
+const TAG_TRUE: u64 = 3;
+
+#[cfg(feature = "nan_boxing")]
+impl Value {
+
+	/// NaNs are canonicalized so their payload never looks like a tag, this covers host data, loaded files and the
+	/// NaN results of arithmetic on values that aren't numbers
+	pub const fn new(value: f64) -> Self {
+		match value.is_nan() {
+			true => Self(f64::NAN.to_bits()),
+			false => Self(value.to_bits()),
+		}
+	}
+
+	pub const fn nil() -> Self {
+		Self(QNAN | TAG_NIL)
+	}
+
+	pub const fn boolean(value: bool) -> Self {
+		match value {
+			true => Self(QNAN | TAG_TRUE),
+			false => Self(QNAN | TAG_FALSE),
+		}
+	}
+
+	pub const fn is_nil(&self) -> bool {
+		self.0 == QNAN | TAG_NIL
+	}
+
+	/// Returns the boolean, or `None` if the value isn't a boolean
+	pub const fn as_bool(&self) -> Option<bool> {
+		match self.0 {
+			bits if bits == QNAN | TAG_TRUE => Some(true),
+			bits if bits == QNAN | TAG_FALSE => Some(false),
+			_ => None,
+		}
+	}
+
+	/// Returns the number, or `None` if the value isn't a number
+	pub const fn as_number(&self) -> Option<f64> {
+		match self.0 & QNAN == QNAN {
+			true => None,
+			false => Some(f64::from_bits(self.0)),
+		}
+	}
+
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+impl Value {
+
+	/// Returns the number, arithmetic treats operands that aren't numbers as NaN
+	#[inline]
+	fn operand(&self) -> f64 {
+		self.as_number().unwrap_or(f64::NAN)
+	}
 
 }
 
+#[cfg(feature = "nan_boxing")]
 impl Value {
 
-	pub fn new(value: f64) -> Self {
-		Self { value: value }
+	/// Returns the bits as a double, values that aren't numbers are quiet NaNs already so arithmetic treats them as
+	/// NaN without checking the tag
+	#[inline]
+	fn operand(&self) -> f64 {
+		f64::from_bits(self.0)
 	}
 
+}
+
+impl Value {
+
 	pub fn negate(&mut self) {
-		self.value = -self.value;
+		*self = Self::new(-self.operand());
 	}
 
 	pub fn add(&mut self, other: &Value) {
-		self.value += other.value;
+		*self = Self::new(self.operand() + other.operand());
 	}
 
 	pub fn subtract(&mut self, other: &Value) {
-		self.value -= other.value;
+		*self = Self::new(self.operand() - other.operand());
 	}
 
 	pub fn multiply(&mut self, other: &Value) {
-		self.value *= other.value;
+		*self = Self::new(self.operand() * other.operand());
 	}
 
 	pub fn divide(&mut self, other: &Value) {
-		self.value /= other.value;
+		*self = Self::new(self.operand() / other.operand());
+	}
+
+}
+
+/// Numbers compare like `f64`, so NaN isn't equal to itself, other values are equal when they are the same
+impl PartialEq for Value {
+
+	fn eq(&self, other: &Self) -> bool {
+		match (self.as_number(), other.as_number()) {
+			(Some(number), Some(other_number)) => number == other_number,
+			(None, None) => self.as_bool() == other.as_bool() && self.is_nil() == other.is_nil(),
+			_ => false,
+		}
+	}
+
+}
+
+impl fmt::Debug for Value {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match (self.as_number(), self.as_bool()) {
+			(Some(number), _) => write!(f, "Number({number:?})"),
+			(_, Some(value)) => write!(f, "Bool({value})"),
+			_ => write!(f, "Nil"),
+		}
 	}
 
 }
@@ -38,11 +190,16 @@ impl Value {
 impl fmt::Display for Value {
 
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "{}", self.value)
+		match (self.as_number(), self.as_bool()) {
+			(Some(number), _) => write!(f, "{number}"),
+			(_, Some(value)) => write!(f, "{value}"),
+			_ => write!(f, "nil"),
+		}
 	}
 
 }
 
+#[derive(Clone)]
 pub struct ValueArray {
 
 	pub values: Vec<Value>,
@@ -60,3 +217,76 @@ impl ValueArray {
 	}
 
 }
+
+impl Default for ValueArray {
+
+	fn default() -> Self {
+		Self::new()
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn value_should_keep_its_kind() {
+		let values = [ Value::nil(), Value::boolean(false), Value::boolean(true), Value::new(1.5) ];
+
+		let kinds: Vec<_> = values.iter().map(|value| (value.is_nil(), value.as_bool(), value.as_number())).collect();
+
+		assert_eq!(kinds, [
+			(true, None, None),
+			(false, Some(false), None),
+			(false, Some(true), None),
+			(false, None, Some(1.5)),
+		]);
+	}
+
+	#[test]
+	fn nan_should_remain_a_number() {
+		let nan_bits = [ f64::NAN.to_bits(), 0xfff8_0000_0000_0000, 0x7ffc_0000_0000_0001, 0x7fff_ffff_ffff_ffff ];
+
+		for bits in nan_bits {
+			let sut = Value::new(f64::from_bits(bits));
+
+			assert!(sut.as_number().is_some_and(f64::is_nan), "{bits:x}");
+			assert_ne!(sut, sut);
+		}
+	}
+
+	#[test]
+	fn arithmetic_should_follow_ieee_754() {
+		let mut sut = Value::new(1.0);
+
+		sut.divide(&Value::new(0.0));
+
+		assert_eq!(sut, Value::new(f64::INFINITY));
+	}
+
+	#[test]
+	fn display_should_format_each_kind() {
+		let values = [ Value::nil(), Value::boolean(true), Value::new(-0.5) ];
+
+		let formatted: Vec<_> = values.iter().map(Value::to_string).collect();
+
+		assert_eq!(formatted, [ "nil", "true", "-0.5" ]);
+	}
+
+	#[test]
+	fn arithmetic_should_turn_other_values_into_nan() {
+		let mut sut = Value::nil();
+
+		sut.add(&Value::new(1.0));
+
+		assert!(sut.as_number().is_some_and(f64::is_nan));
+	}
+
+	#[cfg(feature = "nan_boxing")]
+	#[test]
+	fn nan_boxed_value_should_be_a_single_word() {
+		assert_eq!(std::mem::size_of::<Value>(), 8);
+	}
+
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+
+use crate::chunk::Chunk;
+use crate::op::decode_instruction;
+
+/// Reasons a chunk can be rejected by [verify], each with the code offset of the offending instruction
+#[derive(PartialEq)] #[derive(Debug)]
+pub enum VerifyError {
+
+	UnknownOpcode { offset: usize, opcode: u8 },
+
+	/// Occurs when the operands of the last instruction run past the end of the code
+	TruncatedInstruction { offset: usize },
+
+	/// Occurs when [crate::op::Op::Wide] prefixes an instruction without an operand
+	InvalidWide { offset: usize },
+
+	MissingConstant { offset: usize, index: usize },
+
+	StackUnderflow { offset: usize },
+
+	StackOverflow { offset: usize },
+
+}
+
+impl fmt::Display for VerifyError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::UnknownOpcode { offset, opcode } => write!(f, "Unknown opcode 0x{opcode:02x} at offset {offset}."),
+			Self::TruncatedInstruction { offset } => write!(f, "Truncated instruction at offset {offset}."),
+			Self::InvalidWide { offset } => write!(f, "Widened instruction without operand at offset {offset}."),
+			Self::MissingConstant { offset, index } => write!(f, "Undefined constant {index} at offset {offset}."),
+			Self::StackUnderflow { offset } => write!(f, "Stack underflow at offset {offset}."),
+			Self::StackOverflow { offset } => write!(f, "Stack overflow at offset {offset}."),
+		}
+	}
+
+}
+
+/// Checks that the chunk can be run safely: every opcode is known, operands lie within the code, constants
+/// exist and the stack never drops below zero or grows beyond `max_stack_depth` values, starting with
+/// `stack_depth` values already on the stack. Returns the deepest the stack gets.
+pub fn verify(chunk: &Chunk, stack_depth: usize, max_stack_depth: usize) -> Result<usize, VerifyError> {
+	let mut depth = stack_depth;
+	let mut deepest = stack_depth;
+	let mut offset = 0;
+	while offset < chunk.code.len() {
+		let decoded = decode_instruction(chunk, offset)?;
+		let (pops, pushes) = decoded.op.stack_effect();
+		depth = depth.checked_sub(pops).ok_or(VerifyError::StackUnderflow { offset })? + pushes;
+		if depth > max_stack_depth {
+			return Err(VerifyError::StackOverflow { offset });
+		}
+		deepest = deepest.max(depth);
+		offset += decoded.size;
+	}
+	Ok(deepest)
+}
+
+/// A chunk that passed [verify], which the VM runs without checking its code again
+#[derive(Clone)] #[derive(Copy)]
+pub struct VerifiedChunk<'a> {
+
+	chunk: &'a Chunk,
+
+	/// Most values the chunk has on the stack on top of those it started with
+	max_stack_growth: usize,
+
+}
+
+impl<'a> VerifiedChunk<'a> {
+
+	/// Verifies the chunk for a stack already holding `stack_depth` values, without limiting the stack depth
+	///
+	/// The stack limit is checked by the VM against [VerifiedChunk::max_stack_growth] before running the chunk.
+	/// It still checks for stack underflow as the stack may hold fewer values when the chunk runs.
+	pub fn new(chunk: &'a Chunk, stack_depth: usize) -> Result<Self, VerifyError> {
+		let deepest = verify(chunk, stack_depth, usize::MAX)?;
+		Ok(Self { chunk, max_stack_growth: deepest - stack_depth })
+	}
+
+	pub fn chunk(&self) -> &'a Chunk {
+		self.chunk
+	}
+
+	pub fn max_stack_growth(&self) -> usize {
+		self.max_stack_growth
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::op::Op;
+	use crate::op::WIDE_OPERAND_SIZE;
+	use crate::value::Value;
+
+	#[test]
+	fn verify_should_accept_well_formed_chunk() {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.write_constant(Value::new(2.0), 1).unwrap();
+		chunk.write(Op::Add, 1);
+		chunk.write(Op::Return, 1);
+
+		assert_eq!(verify(&chunk, 0, 2), Ok(2));
+	}
+
+	#[test]
+	fn verify_should_reject_unknown_opcode() {
+		let mut chunk = Chunk::new();
+		chunk.write(0xffu8, 1);
+
+		assert_eq!(verify(&chunk, 0, 8), Err(VerifyError::UnknownOpcode { offset: 0, opcode: 0xff }));
+	}
+
+	#[test]
+	fn verify_should_reject_truncated_instruction() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Constant, 1);
+
+		assert_eq!(verify(&chunk, 0, 8), Err(VerifyError::TruncatedInstruction { offset: 0 }));
+	}
+
+	#[test]
+	fn verify_should_reject_widened_instruction_without_operand() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Wide, 1);
+		chunk.write(Op::Negate, 1);
+		for _ in 0..WIDE_OPERAND_SIZE {
+			chunk.write(0u8, 1);
+		}
+
+		assert_eq!(verify(&chunk, 1, 8), Err(VerifyError::InvalidWide { offset: 0 }));
+	}
+
+	#[test]
+	fn verify_should_reject_missing_constant() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Constant, 1);
+		chunk.write(3u8, 1);
+
+		assert_eq!(verify(&chunk, 0, 8), Err(VerifyError::MissingConstant { offset: 0, index: 3 }));
+	}
+
+	#[test]
+	fn verify_should_reject_stack_underflow() {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.write(Op::Add, 1);
+
+		assert_eq!(verify(&chunk, 0, 8), Err(VerifyError::StackUnderflow { offset: 2 }));
+	}
+
+	#[test]
+	fn verify_should_count_values_already_on_the_stack() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Add, 1);
+
+		assert_eq!(verify(&chunk, 2, 8), Ok(2));
+	}
+
+	#[test]
+	fn verify_should_reject_exceeding_max_stack_depth() {
+		let mut chunk = Chunk::new();
+		for _ in 0..3 {
+			chunk.write_constant(Value::new(1.0), 1).unwrap();
+		}
+
+		assert_eq!(verify(&chunk, 0, 2), Err(VerifyError::StackOverflow { offset: 4 }));
+	}
+
+	#[test]
+	fn new_should_record_stack_growth_above_values_already_on_the_stack() {
+		let mut chunk = Chunk::new();
+		chunk.write(Op::Add, 1);
+		for _ in 0..3 {
+			chunk.write_constant(Value::new(1.0), 1).unwrap();
+		}
+
+		let sut = VerifiedChunk::new(&chunk, 2).unwrap();
+
+		assert_eq!(sut.max_stack_growth(), 2);
+	}
+
+}
@@ -1,10 +1,20 @@
+use std::fmt;
+use std::io::Write;
 use std::ops::Range;
+use std::ops::RangeInclusive;
 
 use sysexits::ExitCode;
 
 use crate::chunk::Chunk;
-use crate::op::*;
+use crate::chunk::Span;
+use crate::compiler::CompileError;
+use crate::op::Op;
+use crate::op::read_wide_operand;
+use crate::output::Sinks;
 use crate::value::Value;
+use crate::verifier;
+use crate::verifier::VerifiedChunk;
+use crate::verifier::VerifyError;
 
 /// Possible error cases during chunk interpreting
 #[derive(PartialEq)] #[derive(Debug)]
@@ -13,9 +23,25 @@ pub enum InterpretError {
 	/// Occurs when a chunk was badly formatted (eg. the bytes don't match with opcodes + operands)
 	BadChunk,
 
-	Compile,
+	/// Carries every error found while compiling the source
+	Compile(Vec<CompileError>),
 
-	Runtime,
+	Runtime(RuntimeError),
+
+}
+
+impl fmt::Display for InterpretError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::BadChunk => write!(f, "Bad chunk."),
+			Self::Compile(compile_errors) => {
+				let messages: Vec<String> = compile_errors.iter().map(CompileError::to_string).collect();
+				write!(f, "{}", messages.join("\n"))
+			},
+			Self::Runtime(runtime_error) => write!(f, "{runtime_error}"),
+		}
+	}
 
 }
 
@@ -24,129 +50,420 @@ impl InterpretError {
 	pub fn to_exit_code(&self) -> ExitCode {
 		match self {
 			Self::BadChunk => ExitCode::Software,
-			Self::Compile => ExitCode::DataErr,
-			Self::Runtime => ExitCode::Software,
+			Self::Compile(_) => ExitCode::DataErr,
+			Self::Runtime(_) => ExitCode::Software,
+		}
+	}
+
+}
+
+/// Error raised while executing a chunk, formatted as the message followed by the stack trace
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct RuntimeError {
+
+	pub message: String,
+
+	/// Source location of the instruction that failed, `None` if the error didn't originate from bytecode
+	pub span: Option<Span>,
+
+	/// Call frames at the moment of the error, innermost first
+	pub stack_trace: Vec<TraceFrame>,
+
+}
+
+impl RuntimeError {
+
+	pub fn new(message: &str) -> Self {
+		Self { message: message.to_string(), span: None, stack_trace: Vec::new() }
+	}
+
+	/// Creates the error raised by the instruction at the given code offset of the top-level script
+	pub fn at(message: &str, chunk: &Chunk, offset: usize) -> Self {
+		let span = chunk.find_span(offset);
+		let stack_trace = vec![ TraceFrame { span, function: None } ];
+		Self { message: message.to_string(), span, stack_trace }
+	}
+
+}
+
+impl fmt::Display for RuntimeError {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.message)?;
+		for frame in &self.stack_trace {
+			write!(f, "\n{frame}")?;
+		}
+		Ok(())
+	}
+
+}
+
+/// A single call frame in the stack trace of a [RuntimeError], formatted like `[line 3:5-9] in script`
+#[derive(PartialEq)] #[derive(Debug)]
+pub struct TraceFrame {
+
+	/// Source location of the instruction executing in this frame, the columns are left out when unknown
+	pub span: Option<Span>,
+
+	/// Name of the function executing in this frame, `None` for the top-level script
+	pub function: Option<String>,
+
+}
+
+impl fmt::Display for TraceFrame {
+
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.span {
+			Some(span) if span.columns.is_known() => write!(f, "[line {}:{}] in ", span.line, span.columns)?,
+			Some(span) => write!(f, "[line {}] in ", span.line)?,
+			None => write!(f, "[line ?] in ")?,
+		}
+		match &self.function {
+			Some(function) => write!(f, "{function}()"),
+			None => write!(f, "script"),
 		}
 	}
 
 }
 
+/// How much of the execution a [VM] traces to its trace output
+#[derive(Clone)] #[derive(Copy)] #[derive(PartialEq)] #[derive(Debug)]
+pub enum TraceMode {
+
+	Off,
+
+	/// Traces every instruction before it is executed
+	Ops,
+
+	/// Traces the stack contents followed by the instruction, before every instruction is executed
+	OpsAndStack,
+
+}
+
+/// Result of executing a single instruction, errors carry the runtime error message
+type OpResult = Result<(), &'static str>;
+
+/// Operations shared by the stack [VM] and the [crate::register::RegisterVM], so either can run a chunk
+pub trait Backend {
+
+	/// Runs a stack chunk, malformed chunks are rejected with [InterpretError::BadChunk]
+	fn interpret(&mut self, chunk: &Chunk) -> Result<(), InterpretError>;
+
+	/// Replaces the sink receiving program output, flushing the previous one
+	fn set_output(&mut self, writer: Box<dyn Write>);
+
+	/// Replaces the sink receiving error reports, flushing the previous one
+	fn set_error_output(&mut self, writer: Box<dyn Write>);
+
+	/// Selects how much of the execution is traced, tracing is off by default
+	fn set_trace_mode(&mut self, trace_mode: TraceMode);
+
+	/// Restricts tracing to instructions on the given source lines, `None` traces all lines
+	fn set_trace_lines(&mut self, trace_lines: Option<RangeInclusive<u32>>);
+
+	/// Replaces the sink receiving instruction traces, flushing the previous one
+	fn set_trace_output(&mut self, writer: Box<dyn Write>);
+
+	/// Writes an error that occurred outside of the machine, eg. while compiling, to the error output
+	fn report_error(&mut self, error: &dyn fmt::Display);
+
+}
+
+/// Tells whether instructions on the line are traced, instructions without a line only are when all lines are
+pub(crate) fn is_traced_line(trace_lines: &Option<RangeInclusive<u32>>, line: Option<u32>) -> bool {
+	trace_lines.as_ref().is_none_or(|trace_lines| line.is_some_and(|line| trace_lines.contains(&line)))
+}
+
+/// A function implemented by the host, receives its arguments and returns a value or an error message
+pub type NativeFn = Box<dyn FnMut(&[Value]) -> Result<Value, String>>;
+
 pub struct VM<const N_STACK_SIZE: usize> {
 
+	/// Inline stack storage, used until the stack grows onto the heap
 	stack: [Value;N_STACK_SIZE],
 
+	/// Heap stack storage, empty until the inline stack is exhausted
+	heap_stack: Vec<Value>,
+
+	/// Maximum number of values the stack may grow to, defaults to `N_STACK_SIZE`
+	max_stack_size: usize,
+
+	/// Start of the stack storage currently in use
+	stack_base: *mut Value,
+
+	/// End of the stack storage currently in use
+	stack_end: *const Value,
+
 	stack_top: *mut Value,
 
+	natives: Vec<(String, NativeFn)>,
+
+	sinks: Sinks,
+
+	trace_mode: TraceMode,
+
+	/// Only instructions on these source lines are traced, all are if `None`
+	trace_lines: Option<RangeInclusive<u32>>,
+
 }
 
 impl<const N_STACK_SIZE: usize> VM<N_STACK_SIZE> {
 
 	pub fn new() -> Self {
-		Self { stack: [Value::new(0.0);N_STACK_SIZE], stack_top: std::ptr::null_mut() }
+		Self {
+			stack: [Value::new(0.0);N_STACK_SIZE],
+			heap_stack: Vec::new(),
+			max_stack_size: N_STACK_SIZE,
+			stack_base: std::ptr::null_mut(),
+			stack_end: std::ptr::null(),
+			stack_top: std::ptr::null_mut(),
+			natives: Vec::new(),
+			sinks: Sinks::new(),
+			trace_mode: TraceMode::Off,
+			trace_lines: None,
+		}
+	}
+
+	/// Allows the stack to grow onto the heap up to the given number of values once the `N_STACK_SIZE`
+	/// values of the inline stack are used, a maximum below `N_STACK_SIZE` has no effect
+	pub fn set_max_stack_size(&mut self, max_stack_size: usize) {
+		self.max_stack_size = max_stack_size;
+	}
+
+	/// Replaces the sink receiving program output, flushing the previous one
+	pub fn set_output(&mut self, writer: Box<dyn Write>) {
+		self.sinks.set_output(writer);
+	}
+
+	/// Replaces the sink receiving error reports, flushing the previous one
+	pub fn set_error_output(&mut self, writer: Box<dyn Write>) {
+		self.sinks.set_error_output(writer);
+	}
+
+	/// Selects how much of the execution is traced, tracing is off by default
+	pub fn set_trace_mode(&mut self, trace_mode: TraceMode) {
+		self.trace_mode = trace_mode;
+	}
+
+	/// Restricts tracing to instructions on the given source lines, `None` traces all lines
+	pub fn set_trace_lines(&mut self, trace_lines: Option<RangeInclusive<u32>>) {
+		self.trace_lines = trace_lines;
+	}
+
+	/// Replaces the sink receiving instruction traces, flushing the previous one
+	pub fn set_trace_output(&mut self, writer: Box<dyn Write>) {
+		self.sinks.set_trace_output(writer);
 	}
 
+	/// Verifies and runs the chunk, chunks that fail verification are rejected with [InterpretError::BadChunk]
 	pub fn interpret(&mut self, chunk: &Chunk) -> Result<(), InterpretError> {
-		if self.stack_top.is_null() {
-			self.stack_top = self.stack.as_mut_ptr();
+		self.bind_stack();
+		// the stack limit isn't verified, overflowing it is a runtime error that comes with a stack trace
+		match VerifiedChunk::new(chunk, self.stack_depth()) {
+			Ok(verified_chunk) => self.run_verified(verified_chunk),
+			Err(verify_error) => {
+				let _ = writeln!(self.sinks.error_output, "{verify_error}");
+				let _ = writeln!(self.sinks.error_output, "{}", InterpretError::BadChunk);
+				self.sinks.flush();
+				Err(InterpretError::BadChunk)
+			},
 		}
+	}
+
+	/// Runs a chunk verified earlier, so chunks run repeatedly only have to be verified once
+	pub fn run_verified(&mut self, verified_chunk: VerifiedChunk) -> Result<(), InterpretError> {
+		self.bind_stack();
+		let chunk = verified_chunk.chunk();
 		// apparently dereferencing raw pointers is faster than indexing a vector, so setting up pointers
 		let ptr_range = chunk.get_code_pointer_range();
-		// if range is empty, the first iteration of the loop would trigger undefined behavior
-		if ptr_range.is_empty() {
-			return Ok(());
+		// room for the deepest stack is made up front, so pushes don't have to check for it
+		let result = match self.reserve_stack(self.stack_depth() + verified_chunk.max_stack_growth()) {
+			Ok(()) if self.trace_mode == TraceMode::Off => self.run::<false>(chunk, ptr_range),
+			Ok(()) => self.run::<true>(chunk, ptr_range),
+			Err(_) => Err(self.stack_limit_error(chunk)),
+		};
+		if let Err(interpret_error) = &result {
+			let _ = writeln!(self.sinks.error_output, "{interpret_error}");
 		}
-		self.run(chunk, ptr_range)
+		self.sinks.flush();
+		result
+	}
+
+	/// Writes an error that occurred outside of the VM, eg. while compiling, to the error output
+	pub fn report_error(&mut self, error: &dyn fmt::Display) {
+		self.sinks.report_error(error);
+	}
+
+	/// Pushes a value onto the stack, fails when the stack is full
+	pub fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+		self.bind_stack();
+		self.reserve_stack(self.stack_depth() + 1).map_err(|message| InterpretError::Runtime(RuntimeError::new(message)))?;
+		self.stack_push(value);
+		Ok(())
+	}
+
+	/// Pops a value from the stack, fails when the stack is empty
+	pub fn pop(&mut self) -> Result<Value, InterpretError> {
+		self.bind_stack();
+		self.stack_pop().map_err(|message| InterpretError::Runtime(RuntimeError::new(message)))
+	}
+
+	/// Registers a native function under the given name, replacing any earlier one with the same name
+	pub fn define_native(&mut self, name: &str, function: NativeFn) {
+		match self.natives.iter_mut().find(|(native_name, _)| native_name == name) {
+			Some(native) => native.1 = function,
+			None => self.natives.push((name.to_string(), function)),
+		}
+	}
+
+	/// Calls the native function with the given name, returns `None` if no such native exists
+	pub fn call_native(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+		let (_, function) = self.natives.iter_mut().find(|(native_name, _)| native_name == name)?;
+		Some(function(args))
+	}
+
+	/// Discards all transient state from previous runs, natives and sinks are kept
+	pub fn reset(&mut self) {
+		self.heap_stack = Vec::new();
+		self.stack_base = std::ptr::null_mut();
+		self.stack_end = std::ptr::null();
+		self.stack_top = std::ptr::null_mut();
+	}
+
+	/// Returns the values currently on the stack, bottom first
+	pub fn stack(&self) -> &[Value] {
+		let depth = self.stack_depth();
+		match self.heap_stack.is_empty() {
+			true => &self.stack[..depth],
+			false => &self.heap_stack[..depth],
+		}
+	}
+
+	/// Returns the number of values currently on the stack
+	pub fn stack_depth(&self) -> usize {
+		// compares addresses only, the pointers may be stale if the VM moved since the last call
+		(self.stack_top.addr() - self.stack_base.addr()) / std::mem::size_of::<Value>()
 	}
 
-	/// Runs the instructions in the given range of pointers
-	fn run(&mut self, chunk: &Chunk, ptr_range: Range<*const u8>) -> Result<(), InterpretError> {
+	/// Returns the number of values the stack can hold before it has to grow
+	pub fn stack_capacity(&self) -> usize {
+		self.heap_stack.len().max(N_STACK_SIZE)
+	}
+
+	/// Returns the names of all registered native functions
+	pub fn native_names(&self) -> impl Iterator<Item = &str> {
+		self.natives.iter().map(|(name, _)| name.as_str())
+	}
+
+	/// Points the stack pointers at the storage in use, the inline storage moves along with the VM
+	#[inline]
+	fn bind_stack(&mut self) {
+		let depth = self.stack_depth();
+		let Range { start, end } = match self.heap_stack.is_empty() {
+			true => self.stack.as_mut_ptr_range(),
+			false => self.heap_stack.as_mut_ptr_range(),
+		};
+		self.stack_base = start;
+		self.stack_end = end;
+		// Safety: depth never exceeds the capacity of the storage in use
+		self.stack_top = unsafe { start.add(depth) };
+	}
+
+	/// Runs the instructions in the given range of pointers, the tracing code is only compiled into the
+	/// `TRACE` variant so running untraced costs nothing
+	///
+	/// The code must have been verified: opcodes are decoded without checks and operands are read without
+	/// bounds checks, the only check per instruction is whether the end of the code has been reached.
+	fn run<const TRACE: bool>(&mut self, chunk: &Chunk, ptr_range: Range<*const u8>) -> Result<(), InterpretError> {
 		// ip is modified a lot and so is kept as a local variable to keep it close / cacheable
 		let Range { start: mut ip, end: end_ptr } = ptr_range;
-		loop {
+		while ip < end_ptr {
 			// create a copy of the pointer to the opcode with operands
 			let op_ptr = ip;
-			// Safety: ip is never beyond end_ptr at the start of the loop
-			let opcode = unsafe { *ip };
-			#[cfg(feature = "trace")] {
+			if TRACE {
 				self.trace_op(chunk, ip);
 			}
-			// Safety: update and check next ip first to prevent an unsafe ptr dereference
-			unsafe { ip = ip.add(op_size(opcode)); }
-			if ip > end_ptr {
-				return Err(InterpretError::BadChunk); // ip went out of bounds
-			}
-			match opcode {
-				OP_CONSTANT => self.op_constant(chunk, op_ptr),
-				OP_ADD => self.op_add(),
-				OP_SUBTRACT => self.op_subtract(),
-				OP_MULTIPLY => self.op_multiply(),
-				OP_DIVIDE => self.op_divide(),
-				OP_NEGATE => self.op_negate(),
-				OP_RETURN => self.op_return(),
-				OP_CONSTANT_LONG => self.op_constant_long(chunk, op_ptr),
-				_ => return Err(InterpretError::BadChunk)
-			}
-			if ip >= end_ptr { // ip can't be greater than, but greater-check is added for safety
-				break;
+			// Safety: verified chunks only hold known opcodes, each followed by all of its operand bytes
+			let op = unsafe { Op::from_u8_unchecked(*ip) };
+			unsafe { ip = ip.add(op.size()); }
+			let op_result = match op {
+				// Safety: verification has already checked that the operand bytes are in bounds
+				Op::Constant => self.op_constant(chunk, unsafe { *op_ptr.add(1) } as usize),
+				Op::Add => self.op_add(),
+				Op::Subtract => self.op_subtract(),
+				Op::Multiply => self.op_multiply(),
+				Op::Divide => self.op_divide(),
+				Op::Negate => self.op_negate(),
+				Op::Return => self.op_return(),
+				Op::Wide => {
+					// Safety: verification has already checked that the widened opcode and its operand are in bounds
+					let operand = unsafe { read_wide_operand(op_ptr.add(2)) };
+					// Safety: verified chunks only widen known instructions
+					match unsafe { Op::from_u8_unchecked(*op_ptr.add(1)) } {
+						Op::Constant => self.op_constant(chunk, operand),
+						_ => return Err(InterpretError::BadChunk),
+					}
+				},
+			};
+			if let Err(message) = op_result {
+				return Err(self.runtime_error(chunk, op_ptr, message));
 			}
 		}
 		Ok(())
 	}
 
 	#[inline]
-	fn op_constant(&mut self, chunk: &Chunk, ptr: *const u8) {
-		// Safety: run() loop has already checked safety of ptr
-		let const_id = unsafe { *ptr.add(1) };
-		self.stack_push(chunk.get_constant(const_id as usize).clone());
-	}
-
-	#[inline]
-	fn op_add(&mut self) {
-		let b = self.stack_pop();
-		unsafe { (*self.stack_top.offset(-1)).add(&b); }
+	fn op_constant(&mut self, chunk: &Chunk, const_id: usize) -> OpResult {
+		self.stack_push(*chunk.get_constant(const_id));
+		Ok(())
 	}
 
 	#[inline]
-	fn op_subtract(&mut self) {
-		let b = self.stack_pop();
-		unsafe { (*self.stack_top.offset(-1)).subtract(&b); }
+	fn op_add(&mut self) -> OpResult {
+		let b = self.stack_pop()?;
+		self.stack_peek_mut()?.add(&b);
+		Ok(())
 	}
 
 	#[inline]
-	fn op_multiply(&mut self) {
-		let b = self.stack_pop();
-		unsafe { (*self.stack_top.offset(-1)).multiply(&b); }
+	fn op_subtract(&mut self) -> OpResult {
+		let b = self.stack_pop()?;
+		self.stack_peek_mut()?.subtract(&b);
+		Ok(())
 	}
 
 	#[inline]
-	fn op_divide(&mut self) {
-		let b = self.stack_pop();
-		unsafe { (*self.stack_top.offset(-1)).divide(&b); }
+	fn op_multiply(&mut self) -> OpResult {
+		let b = self.stack_pop()?;
+		self.stack_peek_mut()?.multiply(&b);
+		Ok(())
 	}
 
 	#[inline]
-	fn op_negate(&mut self) {
-		unsafe { (*self.stack_top.offset(-1)).negate(); }
+	fn op_divide(&mut self) -> OpResult {
+		let b = self.stack_pop()?;
+		self.stack_peek_mut()?.divide(&b);
+		Ok(())
 	}
 
 	#[inline]
-	fn op_return(&mut self) {
-		println!("{}", self.stack_pop());
+	fn op_negate(&mut self) -> OpResult {
+		self.stack_peek_mut()?.negate();
+		Ok(())
 	}
 
 	#[inline]
-	fn op_constant_long(&mut self, chunk: &Chunk, ptr: *const u8) {
-		// Safety: run() loop has already checked safety of ptr
-		let const_id_bytes: [u8;4] = unsafe { [ 0, *ptr.add(1), *ptr.add(2), *ptr.add(3) ] };
-		let const_id = u32::from_be_bytes(const_id_bytes);
-		self.stack_push(chunk.get_constant(const_id as usize).clone());
+	fn op_return(&mut self) -> OpResult {
+		let value = self.stack_pop()?;
+		let _ = writeln!(self.sinks.output, "{value}");
+		Ok(())
 	}
 
+	/// Pushes without checking for room, which [VM::reserve_stack] must have made
 	#[inline]
 	fn stack_push(&mut self, value: Value) {
-		if self.stack_top.cast_const() >= self.stack.as_ptr_range().end {
-			panic!("Stack overflow");
-		}
+		debug_assert!(self.stack_top.cast_const() < self.stack_end);
+		// Safety: room for the value has been reserved, so stack_top is below stack_end
 		unsafe {
 			*self.stack_top = value;
 			self.stack_top = self.stack_top.add(1);
@@ -154,68 +471,574 @@ impl<const N_STACK_SIZE: usize> VM<N_STACK_SIZE> {
 	}
 
 	#[inline]
-	fn stack_pop(&mut self) -> Value {
-		if self.stack_top == self.stack.as_mut_ptr() {
-			panic!("Stack underflow");
+	fn stack_pop(&mut self) -> Result<Value, &'static str> {
+		if self.stack_top == self.stack_base {
+			return Err("Stack underflow.");
 		}
 		unsafe {
 			self.stack_top = self.stack_top.offset(-1);
-			*self.stack_top
+			Ok(*self.stack_top)
 		}
 	}
 
-	#[cfg(feature = "trace")]
-	fn trace_op(&self, chunk: &Chunk, ptr: *const u8) {
-		print!("          ");
-		let mut stack_ptr = self.stack.as_ptr();
-		while stack_ptr < self.stack_top {
-			unsafe {
-				print!("[ {} ]", *stack_ptr);
-				stack_ptr = stack_ptr.add(1);
-			}
+	/// Returns the value on top of the stack for in-place modification
+	#[inline]
+	fn stack_peek_mut(&mut self) -> Result<&mut Value, &'static str> {
+		if self.stack_top == self.stack_base {
+			return Err("Stack underflow.");
+		}
+		// Safety: stack_top is above stack_base, so the value below it is initialized
+		unsafe { Ok(&mut *self.stack_top.offset(-1)) }
+	}
+
+	/// Makes room for a stack of `depth` values, fails when that exceeds the maximum stack size
+	#[inline]
+	fn reserve_stack(&mut self, depth: usize) -> OpResult {
+		// Safety: both pointers point into the same stack storage
+		let capacity = unsafe { self.stack_end.offset_from(self.stack_base) } as usize;
+		match depth <= capacity {
+			true => Ok(()),
+			false => self.grow_stack(depth),
 		}
-		println!();
+	}
+
+	/// Moves the stack into a heap buffer holding at least `min_capacity` values
+	#[cold]
+	fn grow_stack(&mut self, min_capacity: usize) -> OpResult {
+		if min_capacity > self.max_stack_size {
+			return Err("Stack overflow.");
+		}
+		// Safety: all three pointers point into the same stack storage
+		let capacity = unsafe { self.stack_end.offset_from(self.stack_base) } as usize;
+		let depth = unsafe { self.stack_top.offset_from(self.stack_base) } as usize;
+		let new_capacity = (capacity * 2).max(8).max(min_capacity).min(self.max_stack_size);
+		let mut heap_stack = vec![Value::new(0.0);new_capacity];
+		// Safety: the current storage holds at least `depth` initialized values
+		heap_stack[..depth].copy_from_slice(unsafe { std::slice::from_raw_parts(self.stack_base, depth) });
+		self.heap_stack = heap_stack;
+		let Range { start, end } = self.heap_stack.as_mut_ptr_range();
+		self.stack_base = start;
+		self.stack_end = end;
+		self.stack_top = unsafe { start.add(depth) };
+		Ok(())
+	}
+
+	/// Builds the error for a chunk that doesn't fit onto the stack, located at the first instruction the stack
+	/// would fail at
+	#[cold]
+	fn stack_limit_error(&mut self, chunk: &Chunk) -> InterpretError {
+		let max_depth = self.max_stack_size.max(N_STACK_SIZE);
+		let (message, offset) = match verifier::verify(chunk, self.stack_depth(), max_depth) {
+			Err(VerifyError::StackOverflow { offset }) => ("Stack overflow.", offset),
+			Err(VerifyError::StackUnderflow { offset }) => ("Stack underflow.", offset),
+			// the chunk has been verified, so only the stack can fail
+			_ => return InterpretError::BadChunk,
+		};
+		self.stack_top = self.stack_base;
+		InterpretError::Runtime(RuntimeError::at(message, chunk, offset))
+	}
+
+	/// Builds a runtime error for the instruction at `op_ptr` and resets the stack so the VM can be reused
+	fn runtime_error(&mut self, chunk: &Chunk, op_ptr: *const u8, message: &str) -> InterpretError {
+		// Safety: op_ptr always points into the code of the chunk being run
+		let offset = unsafe { op_ptr.offset_from(chunk.code.as_ptr()) } as usize;
+		self.stack_top = self.stack_base;
+		InterpretError::Runtime(RuntimeError::at(message, chunk, offset))
+	}
+
+	#[cold]
+	fn trace_op(&mut self, chunk: &Chunk, ptr: *const u8) {
 		let start_ptr = chunk.code.as_ptr();
-		// Safety: run() loop already checks if ip is safe relative to start_ptr
-		crate::debug::disassemble_instruction(chunk, unsafe { ptr.offset_from(start_ptr) } as usize);
+		// Safety: ptr always points into the code of the chunk being run
+		let offset = unsafe { ptr.offset_from(start_ptr) } as usize;
+		if !is_traced_line(&self.trace_lines, chunk.find_line(offset)) {
+			return;
+		}
+		if self.trace_mode == TraceMode::OpsAndStack {
+			let _ = write!(self.sinks.trace_output, "          ");
+			let mut stack_ptr = self.stack_base.cast_const();
+			while stack_ptr < self.stack_top {
+				unsafe {
+					let _ = write!(self.sinks.trace_output, "[ {} ]", *stack_ptr);
+					stack_ptr = stack_ptr.add(1);
+				}
+			}
+			let _ = writeln!(self.sinks.trace_output);
+		}
+		if let Some(instruction) = crate::debug::Instruction::decode(chunk, offset) {
+			let _ = writeln!(self.sinks.trace_output, "{instruction}");
+		}
+	}
+
+}
+
+impl<const N_STACK_SIZE: usize> Backend for VM<N_STACK_SIZE> {
+
+	fn interpret(&mut self, chunk: &Chunk) -> Result<(), InterpretError> {
+		VM::interpret(self, chunk)
+	}
+
+	fn set_output(&mut self, writer: Box<dyn Write>) {
+		VM::set_output(self, writer);
+	}
+
+	fn set_error_output(&mut self, writer: Box<dyn Write>) {
+		VM::set_error_output(self, writer);
+	}
+
+	fn set_trace_mode(&mut self, trace_mode: TraceMode) {
+		VM::set_trace_mode(self, trace_mode);
+	}
+
+	fn set_trace_lines(&mut self, trace_lines: Option<RangeInclusive<u32>>) {
+		VM::set_trace_lines(self, trace_lines);
+	}
+
+	fn set_trace_output(&mut self, writer: Box<dyn Write>) {
+		VM::set_trace_output(self, writer);
+	}
+
+	fn report_error(&mut self, error: &dyn fmt::Display) {
+		VM::report_error(self, error);
+	}
+
+}
+
+impl<const N_STACK_SIZE: usize> Default for VM<N_STACK_SIZE> {
+
+	fn default() -> Self {
+		Self::new()
 	}
 
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
 	use super::*;
+	use crate::chunk::Columns;
+	use crate::op::WIDE_OPERAND_SIZE;
+	use crate::output::MemorySink;
+	use crate::register::RegisterVM;
+
+	/// Every machine implementing [Backend], along with a name for assertion messages
+	fn backends() -> [(&'static str, Box<dyn Backend>); 2] {
+		[ ("stack", Box::new(VM::<256>::new())), ("register", Box::new(RegisterVM::new())) ]
+	}
+
+	/// A chunk pushing one value more than the machines have room for, on lines 1 to 3
+	fn overflowing_chunk() -> Chunk {
+		let mut chunk = Chunk::new();
+		for i in 0..257 {
+			chunk.write_constant(Value::new(1.0), i / 100 + 1).unwrap();
+		}
+		chunk
+	}
 
 	#[test]
 	fn interpret_should_error_on_malformed_chunk() {
-		let mut sut = VM::<8>::new();
+		for (name, mut sut) in backends() {
+			sut.set_error_output(Box::new(MemorySink::new()));
+			let mut chunk = Chunk::new();
+			chunk.write(Op::Constant, 1); // Op::Constant is normally followed by one byte of constant id
+
+			let result = sut.interpret(&chunk);
+
+			assert_eq!(result, Result::Err(InterpretError::BadChunk), "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_error_on_unknown_opcode() {
+		for (name, mut sut) in backends() {
+			sut.set_error_output(Box::new(MemorySink::new()));
+			let mut chunk = Chunk::new();
+			chunk.write(0xffu8, 1);
+
+			let result = sut.interpret(&chunk);
+
+			assert_eq!(result, Result::Err(InterpretError::BadChunk), "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_write_returned_value_to_output() {
+		for (name, mut sut) in backends() {
+			let output = MemorySink::new();
+			let mut chunk = Chunk::new();
+			chunk.write_constant(Value::new(1.5), 1).unwrap();
+			chunk.write(Op::Return, 1);
+			sut.set_output(Box::new(output.clone()));
+
+			let _ = sut.interpret(&chunk);
+
+			assert_eq!(output.contents(), "1.5\n", "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_load_widened_constants() {
+		for (name, mut sut) in backends() {
+			let output = MemorySink::new();
+			let mut chunk = Chunk::new();
+			for i in 0..300 {
+				chunk.write_constant(Value::new(i as f64), 1).unwrap();
+				chunk.write(Op::Return, 1);
+			}
+			sut.set_output(Box::new(output.clone()));
+
+			let result = sut.interpret(&chunk);
+
+			assert_eq!(result, Ok(()), "{name}");
+			assert_eq!(output.contents().lines().last(), Some("299"), "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_error_on_widened_instruction_without_operand() {
+		for (name, mut sut) in backends() {
+			let mut chunk = Chunk::new();
+			chunk.write(Op::Wide, 1);
+			chunk.write(Op::Add, 1);
+			for _ in 0..WIDE_OPERAND_SIZE {
+				chunk.write(0u8, 1);
+			}
+			sut.set_error_output(Box::new(MemorySink::new()));
+
+			let result = sut.interpret(&chunk);
+
+			assert_eq!(result, Err(InterpretError::BadChunk), "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_write_errors_to_error_output() {
+		for (name, mut sut) in backends() {
+			let error_output = MemorySink::new();
+			let mut chunk = Chunk::new();
+			chunk.write(Op::Constant, 1);
+			sut.set_error_output(Box::new(error_output.clone()));
+
+			let _ = sut.interpret(&chunk);
+
+			assert_eq!(error_output.contents(), "Truncated instruction at offset 0.\nBad chunk.\n", "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_return_runtime_error_on_full_stack() {
+		for (name, mut sut) in backends() {
+			let error_output = MemorySink::new();
+			sut.set_error_output(Box::new(error_output.clone()));
+
+			let result = sut.interpret(&overflowing_chunk());
+
+			let Err(InterpretError::Runtime(runtime_error)) = result else {
+				panic!("expected runtime error from {name}");
+			};
+			assert_eq!(runtime_error.message, "Stack overflow.", "{name}");
+			assert_eq!(runtime_error.span.map(|span| span.line), Some(3), "{name}");
+			assert_eq!(error_output.contents(), "Stack overflow.\n[line 3] in script\n", "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_reset_stack_after_runtime_error() {
+		for (name, mut sut) in backends() {
+			let mut chunk = Chunk::new();
+			chunk.write_constant(Value::new(1.0), 1).unwrap();
+			chunk.write(Op::Return, 1);
+			sut.set_output(Box::new(MemorySink::new()));
+			sut.set_error_output(Box::new(MemorySink::new()));
+			let _ = sut.interpret(&overflowing_chunk());
+
+			let result = sut.interpret(&chunk);
+
+			assert_eq!(result, Ok(()), "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_reject_chunk_popping_empty_stack() {
+		for (name, mut sut) in backends() {
+			let mut chunk = Chunk::new();
+			chunk.write(Op::Add, 1);
+			sut.set_error_output(Box::new(MemorySink::new()));
+
+			let result = sut.interpret(&chunk);
+
+			assert_eq!(result, Err(InterpretError::BadChunk), "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_reject_chunk_with_missing_constant() {
+		for (name, mut sut) in backends() {
+			let mut chunk = Chunk::new();
+			chunk.write(Op::Constant, 1);
+			chunk.write(0u8, 1);
+			sut.set_error_output(Box::new(MemorySink::new()));
+
+			let result = sut.interpret(&chunk);
+
+			assert_eq!(result, Err(InterpretError::BadChunk), "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_return_runtime_error_on_full_inline_stack() {
 		let mut chunk = Chunk::new();
-		chunk.write(OP_CONSTANT, 1); // OP_CONSTANT is normally followed by one byte of constant id
+		for i in 0..9 {
+			chunk.write_constant(Value::new(1.0), i / 4 + 1).unwrap();
+		}
+		let mut sut = VM::<8>::new();
+		sut.set_error_output(Box::new(MemorySink::new()));
 
 		let result = sut.interpret(&chunk);
 
-		assert_eq!(result, Result::Err(InterpretError::BadChunk));
+		let Err(InterpretError::Runtime(runtime_error)) = result else {
+			panic!("expected runtime error");
+		};
+		assert_eq!(runtime_error.span.map(|span| span.line), Some(3));
+		assert_eq!(runtime_error.to_string(), "Stack overflow.\n[line 3] in script");
 	}
 
-	#[test] #[should_panic]
-	fn interpret_should_panic_on_full_stack() {
+	#[test]
+	fn interpret_should_return_stack_overflow_before_running_the_chunk() {
+		let output = MemorySink::new();
 		let mut chunk = Chunk::new();
-		for i in 0..9 {
-			print!("{i:}");
-			chunk.write_constant(Value::new(1.0), 1);
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.write(Op::Return, 1);
+		for _ in 0..8 {
+			chunk.write_constant(Value::new(1.0), 2).unwrap();
 		}
 		let mut sut = VM::<8>::new();
+		sut.set_output(Box::new(output.clone()));
+		sut.set_error_output(Box::new(MemorySink::new()));
+		sut.push(Value::new(2.0)).unwrap();
 
-		let _ = sut.interpret(&chunk);
+		let result = sut.interpret(&chunk);
+
+		let Err(InterpretError::Runtime(runtime_error)) = result else {
+			panic!("expected runtime error");
+		};
+		assert_eq!(runtime_error.span.map(|span| span.line), Some(2));
+		assert_eq!(output.contents(), "");
+		assert_eq!(sut.stack_depth(), 0);
 	}
 
-	#[test] #[should_panic]
-	fn interpret_should_panic_when_popping_empty_stack() {
+	#[test]
+	fn interpret_should_accept_chunk_using_values_already_on_the_stack() {
+		let output = MemorySink::new();
 		let mut chunk = Chunk::new();
-		chunk.write(OP_ADD, 1);
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.write(Op::Add, 1);
+		chunk.write(Op::Return, 1);
 		let mut sut = VM::<8>::new();
+		sut.set_output(Box::new(output.clone()));
+		sut.push(Value::new(2.0)).unwrap();
 
+		let result = sut.interpret(&chunk);
+
+		assert_eq!(result, Ok(()));
+		assert_eq!(output.contents(), "3\n");
+	}
+
+	#[test]
+	fn pop_should_return_runtime_error_on_empty_stack() {
+		let mut sut = VM::<8>::new();
+
+		let result = sut.pop();
+
+		let Err(InterpretError::Runtime(runtime_error)) = result else {
+			panic!("expected runtime error");
+		};
+		assert_eq!(runtime_error.message, "Stack underflow.");
+	}
+
+	#[test]
+	fn reset_should_clear_stack() {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.write_constant(Value::new(2.0), 1).unwrap();
+		let mut sut = VM::<8>::new();
 		let _ = sut.interpret(&chunk);
+		assert_eq!(sut.stack_depth(), 2);
+
+		sut.reset();
+
+		assert_eq!(sut.stack_depth(), 0);
+		assert!(sut.stack().is_empty());
+	}
+
+	#[test]
+	fn reset_should_keep_natives() {
+		let mut sut = VM::<8>::new();
+		sut.define_native("one", Box::new(|_| Ok(Value::new(1.0))));
+
+		sut.reset();
+
+		assert_eq!(sut.native_names().collect::<Vec<_>>(), [ "one" ]);
+	}
+
+	#[test]
+	fn stack_should_survive_moving_the_vm() {
+		let mut sut = Box::new(VM::<8>::new());
+		let _ = sut.push(Value::new(1.0));
+		let _ = sut.push(Value::new(2.0));
+
+		let mut moved = *sut;
+		let _ = moved.push(Value::new(3.0));
+
+		assert_eq!(moved.stack().iter().map(Value::as_number).collect::<Vec<_>>(), [ Some(1.0), Some(2.0), Some(3.0) ]);
+	}
+
+	#[test]
+	fn interpret_should_grow_stack_up_to_max_stack_size() {
+		let output = MemorySink::new();
+		let mut chunk = Chunk::new();
+		for i in 0..32 {
+			chunk.write_constant(Value::new(i as f64), 1).unwrap();
+		}
+		for _ in 0..31 {
+			chunk.write(Op::Add, 1);
+		}
+		chunk.write(Op::Return, 1);
+		let mut sut = VM::<2>::new();
+		sut.set_max_stack_size(32);
+		sut.set_output(Box::new(output.clone()));
+
+		let result = sut.interpret(&chunk);
+
+		assert_eq!(result, Ok(()));
+		assert_eq!(output.contents(), "496\n");
+	}
+
+	#[test]
+	fn interpret_should_return_runtime_error_when_exceeding_max_stack_size() {
+		let mut chunk = Chunk::new();
+		for _ in 0..33 {
+			chunk.write_constant(Value::new(1.0), 1).unwrap();
+		}
+		let mut sut = VM::<0>::new();
+		sut.set_max_stack_size(32);
+		sut.set_error_output(Box::new(MemorySink::new()));
+
+		let result = sut.interpret(&chunk);
+
+		let Err(InterpretError::Runtime(runtime_error)) = result else {
+			panic!("expected runtime error");
+		};
+		assert_eq!(runtime_error.message, "Stack overflow.");
+	}
+
+	#[test]
+	fn run_verified_should_run_chunk_repeatedly() {
+		let output = MemorySink::new();
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(2.0), 1).unwrap();
+		chunk.write(Op::Return, 1);
+		let verified_chunk = VerifiedChunk::new(&chunk, 0).unwrap();
+		let mut sut = VM::<8>::new();
+		sut.set_output(Box::new(output.clone()));
+
+		let first = sut.run_verified(verified_chunk);
+		let second = sut.run_verified(verified_chunk);
+
+		assert_eq!((first, second), (Ok(()), Ok(())));
+		assert_eq!(output.contents(), "2\n2\n");
+	}
+
+	#[test]
+	fn run_verified_should_return_runtime_error_on_stack_underflow() {
+		let mut chunk = Chunk::new();
+		chunk.set_columns(Columns::new(3, 4));
+		chunk.write(Op::Negate, 1);
+		let mut sut = VM::<8>::new();
+		sut.push(Value::new(1.0)).unwrap();
+		let verified_chunk = VerifiedChunk::new(&chunk, sut.stack_depth()).unwrap();
+		sut.reset();
+		sut.set_error_output(Box::new(MemorySink::new()));
+
+		let result = sut.run_verified(verified_chunk);
+
+		let Err(InterpretError::Runtime(runtime_error)) = result else {
+			panic!("expected runtime error");
+		};
+		assert_eq!(runtime_error.message, "Stack underflow.");
+		assert_eq!(runtime_error.span, Some(Span { file: 0, line: 1, columns: Columns::new(3, 4) }));
+		assert_eq!(runtime_error.to_string(), "Stack underflow.\n[line 1:3-4] in script");
+	}
+
+	/// A chunk of one instruction per line, traced by the tests of both machines
+	pub(crate) fn traced_chunk() -> Chunk {
+		let mut chunk = Chunk::new();
+		chunk.write_constant(Value::new(1.0), 1).unwrap();
+		chunk.write(Op::Negate, 2);
+		chunk.write(Op::Return, 3);
+		chunk
+	}
+
+	#[test]
+	fn interpret_should_not_trace_by_default() {
+		for (name, mut sut) in backends() {
+			let trace_output = MemorySink::new();
+			sut.set_output(Box::new(MemorySink::new()));
+			sut.set_trace_output(Box::new(trace_output.clone()));
+
+			let _ = sut.interpret(&traced_chunk());
+
+			assert_eq!(trace_output.contents(), "", "{name}");
+		}
+	}
+
+	#[test]
+	fn interpret_should_trace_ops() {
+		let trace_output = MemorySink::new();
+		let mut sut = VM::<8>::new();
+		sut.set_output(Box::new(MemorySink::new()));
+		sut.set_trace_output(Box::new(trace_output.clone()));
+		sut.set_trace_mode(TraceMode::Ops);
+
+		let _ = sut.interpret(&traced_chunk());
+
+		assert_eq!(trace_output.contents(), "\
+			0000 0001 OP_CONSTANT      '1'\n\
+			0002 0002 OP_NEGATE        \n\
+			0003 0003 OP_RETURN        \n");
+	}
+
+	#[test]
+	fn interpret_should_trace_ops_and_stack() {
+		let trace_output = MemorySink::new();
+		let mut sut = VM::<8>::new();
+		sut.set_output(Box::new(MemorySink::new()));
+		sut.set_trace_output(Box::new(trace_output.clone()));
+		sut.set_trace_mode(TraceMode::OpsAndStack);
+
+		let _ = sut.interpret(&traced_chunk());
+
+		assert_eq!(trace_output.contents(), "          \n\
+			0000 0001 OP_CONSTANT      '1'\n\
+			\x20         [ 1 ]\n\
+			0002 0002 OP_NEGATE        \n\
+			\x20         [ -1 ]\n\
+			0003 0003 OP_RETURN        \n");
+	}
+
+	#[test]
+	fn interpret_should_only_trace_selected_lines() {
+		for (name, mut sut) in backends() {
+			let trace_output = MemorySink::new();
+			sut.set_output(Box::new(MemorySink::new()));
+			sut.set_trace_output(Box::new(trace_output.clone()));
+			sut.set_trace_mode(TraceMode::Ops);
+			sut.set_trace_lines(Some(2..=3));
+
+			let _ = sut.interpret(&traced_chunk());
+
+			let traced_lines: Vec<String> = trace_output.contents().lines().map(|trace| trace[5..9].to_string()).collect();
+			assert_eq!(traced_lines, [ "0002", "0003" ], "{name}");
+		}
 	}
 
 }
@@ -0,0 +1,82 @@
+/*
+ * Exercises the C interface, run from the repository root with:
+ *   make capi-test
+ */
+#include <stdio.h>
+#include <string.h>
+
+#include "lox.h"
+
+static int failures = 0;
+
+#define CHECK(condition) do { \
+	if (!(condition)) { \
+		fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #condition); \
+		failures++; \
+	} \
+} while (0)
+
+struct buffer {
+	char data[256];
+	size_t length;
+};
+
+static void append(const char *data, size_t length, void *user_data) {
+	struct buffer *buffer = user_data;
+	if (buffer->length + length < sizeof(buffer->data)) {
+		memcpy(buffer->data + buffer->length, data, length);
+		buffer->length += length;
+		buffer->data[buffer->length] = '\0';
+	}
+}
+
+static int scale(const double *args, size_t arg_count, double *result, void *user_data) {
+	if (arg_count != 1) {
+		return LOX_RUNTIME_ERROR;
+	}
+	*result = args[0] * *(double *) user_data;
+	return LOX_OK;
+}
+
+int main(void) {
+	LoxVM *vm = lox_vm_new();
+	double factor = 3.0;
+	double args[] = { 2.0 };
+	double out = 0.0;
+	struct buffer output = { .length = 0 };
+	struct buffer error_output = { .length = 0 };
+
+	CHECK(lox_set_output(vm, append, &output) == LOX_OK);
+	CHECK(lox_set_error_output(vm, append, &error_output) == LOX_OK);
+	CHECK(lox_interpret(vm, "1 + 2") == LOX_OK);
+	CHECK(strcmp(output.data, "3\n") == 0);
+	CHECK(lox_interpret(vm, "1 +") == LOX_COMPILE_ERROR);
+	CHECK(strcmp(lox_last_error(vm), "[line 1] Error at end: Expect expression.") == 0);
+	CHECK(strcmp(error_output.data, "[line 1] Error at end: Expect expression.\n") == 0);
+
+	CHECK(lox_push_number(vm, 1.5) == LOX_OK);
+	CHECK(lox_pop_number(vm, &out) == LOX_OK);
+	CHECK(out == 1.5);
+
+	CHECK(lox_pop_number(vm, &out) == LOX_RUNTIME_ERROR);
+	CHECK(lox_last_error(vm) != NULL && strcmp(lox_last_error(vm), "Stack underflow.") == 0);
+
+	CHECK(lox_push_number(vm, 2.5) == LOX_OK);
+	CHECK(lox_stack_depth(vm) == 1);
+	lox_vm_reset(vm);
+	CHECK(lox_stack_depth(vm) == 0);
+	CHECK(lox_last_error(vm) == NULL);
+
+	CHECK(lox_register_native(vm, "scale", scale, &factor) == LOX_OK);
+	CHECK(lox_call_native(vm, "scale", args, 1, &out) == LOX_OK);
+	CHECK(out == 6.0);
+	CHECK(lox_call_native(vm, "scale", NULL, 0, &out) == LOX_RUNTIME_ERROR);
+	CHECK(strcmp(lox_last_error(vm), "Native function 'scale' failed.") == 0);
+
+	lox_vm_free(vm);
+
+	if (failures == 0) {
+		printf("capi: all checks passed\n");
+	}
+	return failures == 0 ? 0 : 1;
+}